@@ -4,6 +4,8 @@ pub use deku;
 
 use std::string::FromUtf8Error;
 
+use deku::bitvec::{BitSlice, Msb0};
+use deku::ctx::Endian;
 use deku::prelude::*;
 
 #[derive(Debug, PartialEq, DekuRead, DekuWrite)]
@@ -76,6 +78,21 @@ impl SlimeString {
     }
 }
 
+// Reads items until the end of the datagram, for fields that have no length prefix.
+fn read_to_end<'a, T: DekuRead<'a, Endian>>(
+    rest: &'a BitSlice<u8, Msb0>,
+    endian: Endian,
+) -> Result<(&'a BitSlice<u8, Msb0>, Vec<T>), DekuError> {
+    let mut rest = rest;
+    let mut items = Vec::new();
+    while !rest.is_empty() {
+        let (new_rest, item) = T::read(rest, endian)?;
+        rest = new_rest;
+        items.push(item);
+    }
+    Ok((rest, items))
+}
+
 #[derive(Debug, PartialEq, DekuRead, DekuWrite)]
 #[deku(type = "u32")]
 #[deku(endian = "big")]
@@ -85,6 +102,8 @@ pub enum PacketType {
         packet_id: u64,
         quat: SlimeQuaternion,
     },
+    #[deku(id = "0")]
+    Heartbeat { packet_id: u64 },
     #[deku(id = "3")]
     Handshake {
         packet_id: u64,
@@ -104,6 +123,13 @@ pub enum PacketType {
     },
     #[deku(id = "10")]
     Ping { id: u32 },
+    /// Level is a fraction between 0 and 1.
+    #[deku(id = "12")]
+    BatteryLevel {
+        packet_id: u64,
+        voltage: f32,
+        level: f32,
+    },
     #[deku(id = "15")]
     SensorInfo {
         packet_id: u64,
@@ -121,6 +147,132 @@ pub enum PacketType {
     },
     #[deku(id = "21")]
     UserAction { packet_id: u64, typ: u8 },
+    #[deku(id = "22")]
+    FeatureFlags {
+        packet_id: u64,
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        flags: Vec<u8>,
+    },
+    #[deku(id = "100")]
+    Bundle {
+        packet_id: u64,
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        packets: Vec<BundledPacket>,
+    },
     #[deku(id = "55076217")] // u8 array with [3, 'H', 'e', 'y'] as u32
     HandshakeResponse,
 }
+
+/// A packet inside a `PacketType::Bundle`. These share the packet number of the bundle, so it is
+/// left out of the packet data.
+#[derive(Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct BundledPacket {
+    #[deku(update = "self.data.len()")]
+    count: u16,
+    #[deku(count = "count")]
+    data: Vec<u8>,
+}
+impl BundledPacket {
+    pub fn new(mut packet: PacketType) -> Result<Self, DekuError> {
+        let has_packet_id = packet.packet_id_mut().is_some();
+        let mut data = packet.to_bytes()?;
+        if has_packet_id {
+            // The packet number always follows the u32 packet type.
+            data.drain(4..12);
+        }
+        Ok(Self {
+            count: data.len() as _,
+            data,
+        })
+    }
+    /// Size of the packet when written into a bundle.
+    pub fn size(&self) -> usize {
+        2 + self.data.len()
+    }
+}
+
+impl PacketType {
+    /// Sequence number of packets that carry one. Handshakes are always sent with number 0.
+    pub fn packet_id_mut(&mut self) -> Option<&mut u64> {
+        match self {
+            PacketType::Heartbeat { packet_id }
+            | PacketType::Rotation { packet_id, .. }
+            | PacketType::Acceleration { packet_id, .. }
+            | PacketType::BatteryLevel { packet_id, .. }
+            | PacketType::SensorInfo { packet_id, .. }
+            | PacketType::RotationData { packet_id, .. }
+            | PacketType::UserAction { packet_id, .. }
+            | PacketType::FeatureFlags { packet_id, .. }
+            | PacketType::Bundle { packet_id, .. } => Some(packet_id),
+            PacketType::Handshake { .. }
+            | PacketType::Ping { .. }
+            | PacketType::HandshakeResponse => None,
+        }
+    }
+}
+
+/// Packets sent from the SlimeVR Server to trackers.
+/// Every packet except the handshake response carries a packet number, which the server always
+/// sets to 0.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(type = "u32")]
+#[deku(endian = "big")]
+pub enum ServerPacket {
+    #[deku(id = "1")]
+    Heartbeat { packet_id: u64 },
+    #[deku(id = "10")]
+    Ping { packet_id: u64, id: u32 },
+    #[deku(id = "15")]
+    SensorInfo {
+        packet_id: u64,
+        sensor_id: u8,
+        sensor_status: u8,
+    },
+    #[deku(id = "22")]
+    FeatureFlags {
+        packet_id: u64,
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        flags: Vec<u8>,
+    },
+    #[deku(id = "25")]
+    SetConfigFlag {
+        packet_id: u64,
+        sensor_id: u8,
+        flag_id: u16,
+        state: bool,
+    },
+    /// The full message is "\x03Hey OVR =D 5", where 5 is the protocol version.
+    #[deku(id = "55076217")] // u8 array with [3, 'H', 'e', 'y'] as u32
+    HandshakeResponse {
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        message: Vec<u8>,
+    },
+}
+impl ServerPacket {
+    /// Protocol version announced in a handshake response. Very old servers don't send one.
+    pub fn protocol_version(&self) -> Option<u32> {
+        match self {
+            ServerPacket::HandshakeResponse { message } => std::str::from_utf8(message)
+                .ok()?
+                .split_whitespace()
+                .last()?
+                .parse()
+                .ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Bit numbers of the feature flags a server can announce.
+pub mod server_features {
+    pub const BUNDLES: usize = 0;
+    pub const BUNDLES_COMPACT: usize = 1;
+}
+
+/// Checks a bit in feature flags. Bit 0 is the lowest bit of the first byte.
+pub fn has_feature(flags: &[u8], bit: usize) -> bool {
+    flags
+        .get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
@@ -3,7 +3,7 @@ mod tests {
     use deku::{DekuContainerRead, DekuContainerWrite};
     use nalgebra032::{Quaternion, UnitQuaternion};
 
-    use crate::PacketType;
+    use crate::{has_feature, server_features, BundledPacket, PacketType, ServerPacket};
 
     #[test]
     fn handshake() {
@@ -94,6 +94,20 @@ mod tests {
         assert_eq!(acc.to_bytes().unwrap(), data);
     }
     #[test]
+    fn test_battery_level() {
+        let battery = PacketType::BatteryLevel {
+            packet_id: 3,
+            voltage: 3.7,
+            level: 0.5,
+        };
+
+        let data: Vec<u8> = vec![
+            0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 3, 64, 108, 204, 205, 63, 0, 0, 0,
+        ];
+
+        assert_eq!(battery.to_bytes().unwrap(), data);
+    }
+    #[test]
     fn test_user_action() {
         let ua = PacketType::UserAction {
             packet_id: 1,
@@ -109,4 +123,175 @@ mod tests {
         let hr = PacketType::HandshakeResponse;
         assert_eq!(hr.to_bytes().unwrap(), "\x03Hey".as_bytes());
     }
+    #[test]
+    fn test_heartbeat() {
+        let hb = PacketType::Heartbeat { packet_id: 2 };
+        assert_eq!(hb.to_bytes().unwrap(), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+    #[test]
+    fn server_heartbeat() {
+        let data = [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        let result = ServerPacket::from_bytes((&data, 0)).unwrap().1;
+
+        assert_eq!(result, ServerPacket::Heartbeat { packet_id: 0 });
+        assert_eq!(result.to_bytes().unwrap(), data);
+    }
+    #[test]
+    fn server_ping() {
+        let data = [0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4];
+        let result = ServerPacket::from_bytes((&data, 0)).unwrap().1;
+
+        let ping = ServerPacket::Ping {
+            packet_id: 0,
+            id: 16909060,
+        };
+        assert_eq!(result, ping);
+        assert_eq!(ping.to_bytes().unwrap(), data);
+    }
+    #[test]
+    fn server_sensor_info() {
+        let data = [0, 0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 0, 64, 1];
+        let result = ServerPacket::from_bytes((&data, 0)).unwrap().1;
+
+        let sensor_info = ServerPacket::SensorInfo {
+            packet_id: 0,
+            sensor_id: 64,
+            sensor_status: 1,
+        };
+        assert_eq!(result, sensor_info);
+        assert_eq!(sensor_info.to_bytes().unwrap(), data);
+    }
+    #[test]
+    fn server_feature_flags() {
+        let data = [0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 0, 3, 128];
+        let result = ServerPacket::from_bytes((&data, 0)).unwrap().1;
+
+        let flags = ServerPacket::FeatureFlags {
+            packet_id: 0,
+            flags: vec![3, 128],
+        };
+        assert_eq!(result, flags);
+        assert_eq!(flags.to_bytes().unwrap(), data);
+    }
+    #[test]
+    fn server_feature_flags_empty() {
+        let data = [0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 0];
+        let result = ServerPacket::from_bytes((&data, 0)).unwrap().1;
+
+        assert_eq!(
+            result,
+            ServerPacket::FeatureFlags {
+                packet_id: 0,
+                flags: vec![],
+            }
+        );
+    }
+    #[test]
+    fn server_set_config_flag() {
+        let data = [0, 0, 0, 25, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 1];
+        let result = ServerPacket::from_bytes((&data, 0)).unwrap().1;
+
+        let config = ServerPacket::SetConfigFlag {
+            packet_id: 0,
+            sensor_id: 2,
+            flag_id: 1,
+            state: true,
+        };
+        assert_eq!(result, config);
+        assert_eq!(config.to_bytes().unwrap(), data);
+    }
+    #[test]
+    fn server_handshake_response() {
+        let data = "\x03Hey OVR =D 5".as_bytes();
+        let result = ServerPacket::from_bytes((data, 0)).unwrap().1;
+
+        assert_eq!(
+            result,
+            ServerPacket::HandshakeResponse {
+                message: " OVR =D 5".as_bytes().to_vec()
+            }
+        );
+        assert_eq!(result.protocol_version(), Some(5));
+    }
+    #[test]
+    fn server_handshake_response_without_version() {
+        let data = "\x03Hey OVR =D".as_bytes();
+        let result = ServerPacket::from_bytes((data, 0)).unwrap().1;
+
+        assert_eq!(result.protocol_version(), None);
+    }
+    #[test]
+    fn test_packet_id_mut() {
+        let mut ua = PacketType::UserAction {
+            packet_id: 0,
+            typ: 3,
+        };
+        *ua.packet_id_mut().unwrap() = 7;
+        assert_eq!(
+            ua,
+            PacketType::UserAction {
+                packet_id: 7,
+                typ: 3
+            }
+        );
+
+        let mut handshake = PacketType::Handshake {
+            packet_id: 0,
+            board: 0,
+            imu: 0,
+            mcu_type: 0,
+            imu_info: (0, 0, 0),
+            build: 0,
+            firmware: "test".to_string().into(),
+            mac_address: [0; 6],
+        };
+        assert_eq!(handshake.packet_id_mut(), None);
+    }
+    #[test]
+    fn test_bundle() {
+        let bundle = PacketType::Bundle {
+            packet_id: 5,
+            packets: vec![
+                BundledPacket::new(PacketType::UserAction {
+                    packet_id: 0,
+                    typ: 3,
+                })
+                .unwrap(),
+                BundledPacket::new(PacketType::Acceleration {
+                    packet_id: 0,
+                    vector: (0.1, 0.5, 0.9),
+                    sensor_id: Some(32),
+                })
+                .unwrap(),
+            ],
+        };
+
+        let data: Vec<u8> = vec![
+            0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0, 5, 0, 5, 0, 0, 0, 21, 3, 0, 17, 0, 0, 0, 4, 61, 204,
+            204, 205, 63, 0, 0, 0, 63, 102, 102, 102, 32,
+        ];
+
+        assert_eq!(bundle.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, bundle);
+    }
+    #[test]
+    fn test_feature_flags() {
+        let flags = PacketType::FeatureFlags {
+            packet_id: 1,
+            flags: vec![5],
+        };
+        assert_eq!(
+            flags.to_bytes().unwrap(),
+            [0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 1, 5]
+        );
+    }
+    #[test]
+    fn test_has_feature() {
+        let flags = [0b0000_0001, 0b0000_0100];
+        assert!(has_feature(&flags, server_features::BUNDLES));
+        assert!(!has_feature(&flags, server_features::BUNDLES_COMPACT));
+        assert!(has_feature(&flags, 10));
+        assert!(!has_feature(&flags, 16));
+        assert!(!has_feature(&[], server_features::BUNDLES));
+    }
 }
@@ -0,0 +1,254 @@
+//! Lets other programs on this computer read device status and control Wrangler.
+//!
+//! Clients connect over TCP to 127.0.0.1 on the `api_port` setting and send one JSON request per
+//! line, and get one JSON response per line back. Requests look like
+//! `{"command": "rotate", "serial_number": "...", "clockwise": true}`.
+//! After `{"command": "subscribe"}` the connection only receives a status line every 100ms.
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    joycon::{ChannelData, ChannelInfo, ServerStatus, Status},
+    settings::{self, BoolSetting, GYRO_SCALE_RANGE},
+};
+
+/// How often a client checks whether the API has been turned off while it waits for a request.
+const CLIENT_POLL: Duration = Duration::from_millis(100);
+
+/// Latest state from the communication thread.
+#[derive(Default)]
+pub struct Shared {
+    pub devices: ArcSwap<Vec<Status>>,
+    pub server: ArcSwap<ServerStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    Status,
+    Subscribe,
+    Rotate {
+        serial_number: String,
+        clockwise: bool,
+    },
+    SetScale {
+        serial_number: String,
+        scale: f64,
+    },
+    /// Resets using the given device, or any device.
+    Reset {
+        serial_number: Option<String>,
+        /// A full reset instead of a yaw reset.
+        #[serde(default)]
+        full: bool,
+    },
+    SetSetting {
+        setting: BoolSetting,
+        value: bool,
+    },
+    SetAddress {
+        address: String,
+    },
+}
+
+#[derive(Serialize)]
+struct StatusResponse<'a> {
+    server: ServerStatus,
+    devices: &'a [Status],
+}
+
+struct Api {
+    settings: settings::Handler,
+    tx: mpsc::Sender<ChannelData>,
+    shared: Arc<Shared>,
+}
+impl Api {
+    fn status(&self) -> serde_json::Value {
+        let devices = self.shared.devices.load();
+        json!(StatusResponse {
+            server: **self.shared.server.load(),
+            devices: &devices,
+        })
+    }
+
+    fn handle(&self, request: Request) -> Result<serde_json::Value, String> {
+        match request {
+            Request::Status | Request::Subscribe => return Ok(self.status()),
+            Request::Rotate {
+                serial_number,
+                clockwise,
+            } => {
+                self.settings.change(|ws| {
+                    ws.joycon_rotation_add(serial_number, if clockwise { 90 } else { -90 });
+                });
+            }
+            Request::SetScale {
+                serial_number,
+                scale,
+            } => {
+                if !GYRO_SCALE_RANGE.contains(&scale) {
+                    return Err("Scale must be between 0.8 and 1.2.".into());
+                }
+                self.settings
+                    .change(|ws| ws.joycon_scale_set(serial_number, scale));
+            }
+            Request::Reset {
+                serial_number,
+                full,
+            } => {
+                let serial_number = match serial_number {
+                    Some(sn) => sn,
+                    None => match self.shared.devices.load().first() {
+                        Some(status) => status.serial_number.clone(),
+                        None => return Err("No devices connected.".into()),
+                    },
+                };
+                let info = if full {
+                    ChannelInfo::FullReset
+                } else {
+                    ChannelInfo::Reset
+                };
+                self.tx
+                    .send(ChannelData::new(serial_number, info))
+                    .map_err(|e| e.to_string())?;
+            }
+            Request::SetSetting { setting, value } => {
+                self.settings.change(|ws| *setting.get_mut(ws) = value);
+            }
+            Request::SetAddress { address } => {
+                if address.parse::<std::net::SocketAddr>().is_err() {
+                    return Err(format!("\"{address}\" is not a valid ip with port number."));
+                }
+                self.settings.change(|ws| ws.address = address);
+            }
+        }
+        Ok(json!({ "ok": true }))
+    }
+
+    /// Serves one client until it disconnects or the API is turned off.
+    fn client(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_POLL))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while self.settings.load().api_enabled {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+            let line = std::mem::take(&mut line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request = serde_json::from_str::<Request>(&line);
+            let subscribe = matches!(request, Ok(Request::Subscribe));
+            let response = request
+                .map_err(|e| e.to_string())
+                .and_then(|request| self.handle(request))
+                .unwrap_or_else(|e| json!({ "error": e }));
+            writeln!(writer, "{response}")?;
+
+            while subscribe && self.settings.load().api_enabled {
+                thread::sleep(Duration::from_millis(100));
+                writeln!(writer, "{}", self.status())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Listens for clients while the API is enabled in the settings.
+pub fn spawn_thread(
+    settings: settings::Handler,
+    tx: mpsc::Sender<ChannelData>,
+    shared: Arc<Shared>,
+) {
+    let api = Arc::new(Api {
+        settings,
+        tx,
+        shared,
+    });
+    let mut listener: Option<(u16, TcpListener)> = None;
+    // Port that could not be bound, which isn't retried until the setting changes.
+    let mut failed_port = None;
+    loop {
+        let (enabled, port) = {
+            let settings = api.settings.load();
+            (settings.api_enabled, settings.api_port)
+        };
+        if !enabled || matches!(&listener, Some((p, _)) if *p != port) {
+            listener = None;
+        }
+        if !enabled {
+            failed_port = None;
+        }
+        if enabled && listener.is_none() && failed_port != Some(port) {
+            match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(l) => {
+                    l.set_nonblocking(true).ok();
+                    println!("[INFO] API listening on 127.0.0.1:{port}");
+                    listener = Some((port, l));
+                }
+                Err(e) => {
+                    println!("\x1b[0;31m[ERROR]\x1b[0m Could not start API on port {port}: {e}");
+                    failed_port = Some(port);
+                }
+            }
+        }
+        if let Some((_, l)) = &listener {
+            while let Ok((stream, _)) = l.accept() {
+                stream.set_nonblocking(false).ok();
+                let api = api.clone();
+                thread::spawn(move || api.client(stream).ok());
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closes_clients_when_turned_off() {
+        let settings = settings::Handler::in_memory(|ws| ws.api_enabled = true);
+        let (tx, _rx) = mpsc::channel();
+        let api = Api {
+            settings: settings.clone(),
+            tx,
+            shared: Arc::default(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        thread::spawn(move || api.client(accepted).ok());
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        writeln!(stream, r#"{{"command": "status"}}"#).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.contains("devices"), "{line}");
+
+        settings.change(|ws| ws.api_enabled = false);
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{env, net::SocketAddr};
+
+use thiserror::Error;
+
+use crate::{
+    settings::{self, BoolSetting, GYRO_SCALE_RANGE},
+    steam_blacklist as blacklist,
+};
+
+const HELP: &str = "\
+Usage: slimevr-wrangler [COMMAND]
+
+Without a command the app window is opened.
+
+Commands:
+  devices list                           List Joy-Cons saved in the config
+  device set-rotation <serial> <degrees> Set the rotation of a Joy-Con, in steps of 90
+  device set-scale <serial> <scale>      Set the gyro scale ratio of all axes of a Joy-Con
+                                         (0.8 to 1.2)
+  server set-address <ip:port>           Set the SlimeVR Server address
+  config show                            Print the config file
+  config set <setting> <on|off>          Change a setting: send-reset, keep-ids,
+                                         bundle-packets, separate-trackers, discover-server,
+                                         api-enabled, osc, vmc, taps
+  blacklist check                        Check the Steam controller blacklist
+  blacklist fix                          Add Joy-Cons to the Steam controller blacklist
+  help                                   Show this text
+
+Options:
+  --headless              Run without a window
+  --log-file <file>       Write headless status changes to a file
+  --record <file>         Record all controller data to a file
+  --replay <file>         Play back a recording
+  --replay-speed <speed>  Playback speed of a recording, 0 for as fast as possible
+  test                    Add fake controllers";
+
+/// True if the flag was given, like `--headless`.
+pub fn flag(name: &str) -> bool {
+    env::args().any(|a| a == name)
+}
+
+/// Value after a command line flag, like the file in `--record file.jsonl`.
+pub fn value(name: &str) -> Option<String> {
+    env::args().skip_while(|a| a != name).nth(1)
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CliError {
+    #[error("Unknown command \"{0}\". Use \"help\" to see all commands.")]
+    UnknownCommand(String),
+    #[error("Missing {0}.")]
+    Missing(&'static str),
+    #[error("Unexpected argument \"{0}\".")]
+    Unexpected(String),
+    #[error("\"{0}\" is not a valid {1}.")]
+    Invalid(String, &'static str),
+    #[error("Rotation must be a multiple of 90 degrees.")]
+    Rotation,
+    #[error("Scale must be between 0.8 and 1.2.")]
+    Scale,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    DevicesList,
+    DeviceSetRotation(String, i32),
+    DeviceSetScale(String, f64),
+    ServerSetAddress(SocketAddr),
+    ConfigShow,
+    ConfigSet(BoolSetting, bool),
+    BlacklistCheck,
+    BlacklistFix,
+}
+
+fn next<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+    what: &'static str,
+) -> Result<&'a str, CliError> {
+    args.next().ok_or(CliError::Missing(what))
+}
+
+fn parse_bool(value: &str) -> Result<bool, CliError> {
+    match value {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(CliError::Invalid(value.into(), "on/off value")),
+    }
+}
+
+fn parse_setting(name: &str) -> Result<BoolSetting, CliError> {
+    Ok(match name {
+        "send-reset" => BoolSetting::SendReset,
+        "keep-ids" => BoolSetting::KeepIds,
+        "bundle-packets" => BoolSetting::BundlePackets,
+        "separate-trackers" => BoolSetting::SeparateTrackers,
+        "discover-server" => BoolSetting::DiscoverServer,
+        "api-enabled" => BoolSetting::ApiEnabled,
+        "osc" => BoolSetting::Osc,
+        "vmc" => BoolSetting::Vmc,
+        "taps" => BoolSetting::Taps,
+        _ => return Err(CliError::Invalid(name.into(), "setting")),
+    })
+}
+
+/// Parses the arguments after the program name. Returns `None` if there is no command, and the
+/// app should start normally.
+pub fn parse(args: &[String]) -> Result<Option<Command>, CliError> {
+    let mut args = args.iter().map(String::as_str);
+    let Some(first) = args.next() else {
+        return Ok(None);
+    };
+    if first.starts_with('-') || first == "test" {
+        return Ok(None);
+    }
+    let command = match (first, args.next()) {
+        ("help", None) => Command::Help,
+        ("devices", Some("list")) => Command::DevicesList,
+        ("device", Some("set-rotation")) => {
+            let serial_number = next(&mut args, "serial number")?;
+            let degrees = next(&mut args, "rotation")?;
+            let degrees: i32 = degrees
+                .parse()
+                .map_err(|_| CliError::Invalid(degrees.into(), "rotation"))?;
+            if degrees % 90 != 0 {
+                return Err(CliError::Rotation);
+            }
+            Command::DeviceSetRotation(serial_number.into(), degrees.rem_euclid(360))
+        }
+        ("device", Some("set-scale")) => {
+            let serial_number = next(&mut args, "serial number")?;
+            let scale = next(&mut args, "scale")?;
+            let scale: f64 = scale
+                .parse()
+                .map_err(|_| CliError::Invalid(scale.into(), "scale"))?;
+            if !GYRO_SCALE_RANGE.contains(&scale) {
+                return Err(CliError::Scale);
+            }
+            Command::DeviceSetScale(serial_number.into(), scale)
+        }
+        ("server", Some("set-address")) => {
+            let address = next(&mut args, "address")?;
+            Command::ServerSetAddress(
+                address
+                    .parse()
+                    .map_err(|_| CliError::Invalid(address.into(), "ip address with port"))?,
+            )
+        }
+        ("config", Some("show")) => Command::ConfigShow,
+        ("config", Some("set")) => {
+            let setting = parse_setting(next(&mut args, "setting")?)?;
+            Command::ConfigSet(setting, parse_bool(next(&mut args, "on/off value")?)?)
+        }
+        ("blacklist", Some("check")) => Command::BlacklistCheck,
+        ("blacklist", Some("fix")) => Command::BlacklistFix,
+        (first, Some(second)) => {
+            return Err(CliError::UnknownCommand(format!("{first} {second}")));
+        }
+        (first, None) => return Err(CliError::UnknownCommand(first.into())),
+    };
+    if let Some(extra) = args.next() {
+        return Err(CliError::Unexpected(extra.into()));
+    }
+    Ok(Some(command))
+}
+
+#[tokio::main]
+async fn blacklist(fix: bool) -> blacklist::BlacklistResult {
+    if fix {
+        blacklist::update_blacklist().await
+    } else {
+        blacklist::check_blacklist().await
+    }
+}
+
+pub fn run(command: Command) {
+    let settings = settings::Handler::default();
+    match command {
+        Command::Help => println!("{HELP}"),
+        Command::DevicesList => {
+            let settings = settings.load();
+            if settings.joycon.is_empty() {
+                println!("No Joy-Cons saved in the config yet.");
+            }
+            let mut serial_numbers: Vec<_> = settings.joycon.keys().collect();
+            serial_numbers.sort();
+            for serial_number in serial_numbers {
+                let joycon = &settings.joycon[serial_number];
+                println!(
+                    "{serial_number}: mounting {}, rotation {}, scale {:.3}/{:.3}/{:.3}, id {}, filter {}",
+                    settings
+                        .joycon_preset_get(serial_number)
+                        .map_or("custom".into(), |p| p.to_string()),
+                    settings.joycon_rotation_get(serial_number),
+                    joycon.gyro_scale_factor[0],
+                    joycon.gyro_scale_factor[1],
+                    joycon.gyro_scale_factor[2],
+                    joycon.keep_id,
+                    joycon.filter.kind
+                );
+            }
+        }
+        Command::DeviceSetRotation(serial_number, degrees) => {
+            settings.change(|ws| {
+                let current = ws.joycon_rotation_get(&serial_number);
+                ws.joycon_rotation_add(serial_number.clone(), degrees - current);
+            });
+            println!("Rotation of {serial_number} set to {degrees}.");
+        }
+        Command::DeviceSetScale(serial_number, scale) => {
+            settings.change(|ws| ws.joycon_scale_set(serial_number.clone(), scale));
+            println!("Scale of {serial_number} set to {scale:.3}.");
+        }
+        Command::ServerSetAddress(address) => {
+            settings.change(|ws| ws.address = address.to_string());
+            println!("SlimeVR Server address set to {address}.");
+        }
+        Command::ConfigShow => match serde_json::to_string_pretty(&**settings.load()) {
+            Ok(json) => println!("{json}"),
+            Err(e) => println!("\x1b[0;31m[ERROR]\x1b[0m Could not show config: {e}"),
+        },
+        Command::ConfigSet(setting, value) => {
+            settings.change(|ws| *setting.get_mut(ws) = value);
+            println!("{setting:?} set to {}.", if value { "on" } else { "off" });
+        }
+        Command::BlacklistCheck | Command::BlacklistFix => {
+            let result = blacklist(command == Command::BlacklistFix);
+            if result.visible() {
+                println!("{}", result.info);
+            } else {
+                println!("Steam controller blacklist is set correctly.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Option<Command>, CliError> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn starts_app_without_command() {
+        assert_eq!(parse_str(""), Ok(None));
+        assert_eq!(parse_str("--headless"), Ok(None));
+        assert_eq!(parse_str("test"), Ok(None));
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_str("devices list"), Ok(Some(Command::DevicesList)));
+        assert_eq!(
+            parse_str("device set-rotation ABC -90"),
+            Ok(Some(Command::DeviceSetRotation("ABC".into(), 270)))
+        );
+        assert_eq!(
+            parse_str("device set-scale ABC 1.05"),
+            Ok(Some(Command::DeviceSetScale("ABC".into(), 1.05)))
+        );
+        assert_eq!(
+            parse_str("server set-address 192.168.1.5:6969"),
+            Ok(Some(Command::ServerSetAddress(
+                "192.168.1.5:6969".parse().unwrap()
+            )))
+        );
+        assert_eq!(
+            parse_str("config set keep-ids on"),
+            Ok(Some(Command::ConfigSet(BoolSetting::KeepIds, true)))
+        );
+        assert_eq!(
+            parse_str("config set taps off"),
+            Ok(Some(Command::ConfigSet(BoolSetting::Taps, false)))
+        );
+        assert_eq!(parse_str("blacklist fix"), Ok(Some(Command::BlacklistFix)));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(
+            parse_str("device set-rotation ABC 45"),
+            Err(CliError::Rotation)
+        );
+        assert_eq!(parse_str("device set-scale ABC 2"), Err(CliError::Scale));
+        assert_eq!(
+            parse_str("device set-scale ABC"),
+            Err(CliError::Missing("scale"))
+        );
+        assert_eq!(
+            parse_str("server set-address localhost"),
+            Err(CliError::Invalid(
+                "localhost".into(),
+                "ip address with port"
+            ))
+        );
+        assert_eq!(
+            parse_str("config set keep-ids maybe"),
+            Err(CliError::Invalid("maybe".into(), "on/off value"))
+        );
+        assert_eq!(
+            parse_str("config show extra"),
+            Err(CliError::Unexpected("extra".into()))
+        );
+        assert_eq!(
+            parse_str("devices remove"),
+            Err(CliError::UnknownCommand("devices remove".into()))
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use tokio::time::interval;
+
+use crate::{
+    joycon::{self, Battery, DeviceStatus, ServerStatus},
+    settings,
+};
+
+/// Writes status lines to stdout, or appends them to a log file.
+struct Log {
+    file: Option<File>,
+    start: Instant,
+}
+impl Log {
+    fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Self {
+            file,
+            start: Instant::now(),
+        })
+    }
+    fn line(&mut self, line: &str) {
+        let line = format!("[{:>9.1}s] {line}", self.start.elapsed().as_secs_f64());
+        match self.file.as_mut() {
+            Some(file) => {
+                writeln!(file, "{line}").ok();
+            }
+            None => println!("{line}"),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Runs Wrangler without a window until it gets SIGTERM or Ctrl+C.
+#[tokio::main]
+pub async fn run(log_file: Option<PathBuf>) -> io::Result<()> {
+    let mut log = Log::open(log_file)?;
+    let settings = settings::Handler::default();
+    log.line(&format!(
+        "Starting headless, SlimeVR Server address: {}",
+        if settings.load().discover_server {
+            "searching local network".into()
+        } else {
+            format!("{}", settings.load().get_socket_address())
+        }
+    ));
+    let mut wrapper = joycon::Wrapper::new(settings);
+
+    let mut server_status = ServerStatus::Disconnected;
+    let mut devices: HashMap<String, (DeviceStatus, Battery)> = HashMap::new();
+    let mut poll = interval(Duration::from_millis(100));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = poll.tick() => {}
+        }
+        if let Some(status) = wrapper.poll_server() {
+            if status != server_status {
+                log.line(&format!("SlimeVR Server: {status:?}"));
+                server_status = status;
+            }
+        }
+        for server in wrapper.poll_discovered() {
+            log.line(&format!("Found SlimeVR Server at {server}"));
+        }
+        for status in wrapper.poll_status().unwrap_or_default() {
+            let now = (status.status, status.battery);
+            if devices.get(&status.serial_number) != Some(&now) {
+                log.line(&format!(
+                    "{}: {}, battery {:?}",
+                    status.serial_number, status.status, status.battery
+                ));
+                devices.insert(status.serial_number, now);
+            }
+        }
+    }
+    log.line("Shutting down");
+    wrapper.shutdown();
+    Ok(())
+}
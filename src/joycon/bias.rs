@@ -0,0 +1,161 @@
+//! Finds the gyro bias while a controller is lying still, and removes it from the samples.
+
+use nalgebra::Vector3;
+
+use super::imu::{sample_dt, JoyconAxisData};
+
+/// How far samples can be from the recent average, in radians/s and G, to count as lying still.
+const GYRO_NOISE: f64 = 0.03;
+const ACC_NOISE: f64 = 0.03;
+/// A larger average turn rate is a slow turn and not a bias, in radians/s.
+const MAX_BIAS: f64 = 0.1;
+/// Time constant of the recent average, in seconds.
+const AVERAGE_TIME: f64 = 0.5;
+/// How long it has to be lying still before the bias is updated.
+const REST_TIME: f64 = 1.0;
+/// Time constant of the bias updates.
+const BIAS_TIME: f64 = 2.0;
+/// How long the bias has to be updated before it is trusted.
+const CONVERGED_TIME: f64 = 5.0;
+/// How often the bias is saved while the controller keeps lying still.
+const SAVE_TIME: f64 = 30.0;
+
+pub struct GyroBias {
+    pub bias: Vector3<f64>,
+    gyro_average: Vector3<f64>,
+    acc_average: Vector3<f64>,
+    last_time: f64,
+    /// How long it has been lying still.
+    rest: f64,
+    /// Total time the bias has been updated.
+    learned: f64,
+    learned_saved: f64,
+}
+impl GyroBias {
+    /// Starts from a bias that was saved earlier.
+    pub fn new(bias: [f64; 3]) -> Self {
+        Self {
+            bias: bias.into(),
+            gyro_average: Vector3::zeros(),
+            acc_average: Vector3::zeros(),
+            last_time: 0.0,
+            rest: 0.0,
+            learned: 0.0,
+            learned_saved: 0.0,
+        }
+    }
+
+    /// Returns the sample with the bias removed.
+    pub fn update(&mut self, frame: JoyconAxisData) -> JoyconAxisData {
+        let dt = sample_dt(self.last_time, frame.time);
+        self.last_time = frame.time;
+        let gyro = Vector3::new(frame.gyro_x, frame.gyro_y, frame.gyro_z);
+        let acc = Vector3::new(frame.accel_x, frame.accel_y, frame.accel_z);
+
+        let step = (dt / AVERAGE_TIME).min(1.0);
+        self.gyro_average += (gyro - self.gyro_average) * step;
+        self.acc_average += (acc - self.acc_average) * step;
+        let still = (gyro - self.gyro_average).amax() < GYRO_NOISE
+            && (acc - self.acc_average).amax() < ACC_NOISE
+            && self.gyro_average.amax() < MAX_BIAS;
+        self.rest = if still { self.rest + dt } else { 0.0 };
+
+        if self.rest >= REST_TIME {
+            self.bias += (self.gyro_average - self.bias) * (dt / BIAS_TIME).min(1.0);
+            self.learned += dt;
+        }
+
+        JoyconAxisData {
+            gyro_x: frame.gyro_x - self.bias.x,
+            gyro_y: frame.gyro_y - self.bias.y,
+            gyro_z: frame.gyro_z - self.bias.z,
+            ..frame
+        }
+    }
+
+    /// True when it has been lying still for a while.
+    pub fn still(&self) -> bool {
+        self.rest >= REST_TIME
+    }
+
+    /// Recent average acceleration in G.
+    pub fn acc_average(&self) -> [f64; 3] {
+        self.acc_average.into()
+    }
+
+    pub fn converged(&self) -> bool {
+        self.learned >= CONVERGED_TIME
+    }
+
+    /// Returns the bias when it should be saved: after it has been lying still, and now and then
+    /// while it keeps lying still.
+    pub fn take_unsaved(&mut self) -> Option<[f64; 3]> {
+        let unsaved = self.learned - self.learned_saved;
+        if unsaved > 0.0 && (self.rest < REST_TIME || unsaved >= SAVE_TIME) {
+            self.learned_saved = self.learned;
+            Some(self.bias.into())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the bias when more has been learned since it was saved. Used before exiting.
+    pub fn take_changed(&mut self) -> Option<[f64; 3]> {
+        if self.learned > self.learned_saved {
+            self.learned_saved = self.learned;
+            Some(self.bias.into())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(gyro: [f64; 3], seconds: f64) -> impl Iterator<Item = JoyconAxisData> {
+        (0..(seconds / 0.005) as usize).map(move |i| JoyconAxisData {
+            accel_x: 0.0,
+            accel_y: 0.0,
+            accel_z: 1.0,
+            gyro_x: gyro[0],
+            gyro_y: gyro[1],
+            gyro_z: gyro[2],
+            time: 100.0 + i as f64 * 0.005,
+        })
+    }
+
+    #[test]
+    fn learns_bias_at_rest() {
+        let mut bias = GyroBias::new([0.0; 3]);
+        let mut last = None;
+        for frame in frames([0.02, -0.01, 0.005], 15.0) {
+            last = Some(bias.update(frame));
+        }
+        let last = last.unwrap();
+        assert!(bias.converged());
+        assert!((bias.bias - Vector3::new(0.02, -0.01, 0.005)).amax() < 1e-3);
+        assert!(last.gyro_x.abs() < 1e-3 && last.gyro_y.abs() < 1e-3);
+        // Saved once it is picked up.
+        assert!(bias.take_unsaved().is_none());
+        bias.update(JoyconAxisData {
+            gyro_x: 1.0,
+            time: last.time + 0.005,
+            ..last
+        });
+        assert!(bias.take_unsaved().is_some());
+        assert!(bias.take_unsaved().is_none());
+    }
+
+    #[test]
+    fn ignores_slow_turn() {
+        let mut bias = GyroBias::new([0.0; 3]);
+        for frame in frames([0.0, 0.0, 0.3], 15.0) {
+            bias.update(frame);
+        }
+        assert!(!bias.converged());
+        assert_eq!(bias.bias, Vector3::zeros());
+        assert!(bias.take_unsaved().is_none());
+    }
+}
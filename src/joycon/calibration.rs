@@ -0,0 +1,89 @@
+//! Six-face accelerometer calibration: the controller lies still on each of its six sides, and
+//! the offset and scale of each axis are fitted so that gravity reads as exactly 1G.
+
+use crate::settings::AccelCalibration;
+
+/// How much of gravity has to be on one axis for a face to count.
+const MIN_GRAVITY: f64 = 0.8;
+/// How much of gravity may be on the other axes.
+const MAX_TILT: f64 = 0.3;
+
+/// Names of the faces, in the order they are stored.
+pub const FACES: [&str; 6] = ["X+", "X-", "Y+", "Y-", "Z+", "Z-"];
+
+#[derive(Debug, Clone, Default)]
+pub struct SixFace {
+    /// The reading along the axis that points up or down, for each face.
+    faces: [Option<f64>; 6],
+}
+impl SixFace {
+    /// Adds an average reading taken while lying still. Returns which face it was.
+    pub fn add(&mut self, acc: [f64; 3]) -> Result<usize, &'static str> {
+        let axis = (0..3)
+            .max_by(|a, b| acc[*a].abs().total_cmp(&acc[*b].abs()))
+            .unwrap_or(0);
+        let tilted = (0..3).any(|i| i != axis && acc[i].abs() > MAX_TILT);
+        if acc[axis].abs() < MIN_GRAVITY || tilted {
+            return Err("Lay it flat on one of its sides.");
+        }
+        let face = axis * 2 + usize::from(acc[axis] < 0.0);
+        self.faces[face] = Some(acc[axis]);
+        Ok(face)
+    }
+
+    pub fn has(&self, face: usize) -> bool {
+        self.faces[face].is_some()
+    }
+
+    /// Fits the calibration once all faces are there.
+    pub fn fit(&self) -> Option<Result<AccelCalibration, &'static str>> {
+        let mut calibration = AccelCalibration::default();
+        for axis in 0..3 {
+            let up = self.faces[axis * 2]?;
+            let down = self.faces[axis * 2 + 1]?;
+            calibration.offset[axis] = (up + down) / 2.0;
+            calibration.scale[axis] = 2.0 / (up - down);
+        }
+        let sane = calibration.offset.iter().all(|o| o.abs() < 0.2)
+            && calibration.scale.iter().all(|s| (0.8..1.2).contains(s));
+        Some(if sane {
+            Ok(calibration)
+        } else {
+            Err("The readings are too far off, start over and keep it still on each side.")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_offset_and_scale() {
+        let offset = [0.02, -0.05, 0.01];
+        let gain = [1.05, 0.97, 1.0];
+        let mut six = SixFace::default();
+        for face in 0..6 {
+            assert!(six.fit().is_none());
+            let mut gravity = [0.03, -0.02, 0.01];
+            gravity[face / 2] = if face % 2 == 0 { 1.0 } else { -1.0 };
+            let raw = [0, 1, 2].map(|i| gravity[i] / gain[i] + offset[i]);
+            assert_eq!(six.add(raw), Ok(face));
+        }
+        let calibration = six.fit().unwrap().unwrap();
+        for i in 0..3 {
+            assert!((calibration.offset[i] - offset[i]).abs() < 1e-9);
+            assert!((calibration.scale[i] - gain[i]).abs() < 1e-9);
+        }
+        let corrected = calibration.apply([0.0, 1.0 / gain[1] + offset[1], offset[2]]);
+        assert!((corrected[1] - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_tilted() {
+        let mut six = SixFace::default();
+        assert!(six.add([0.7, 0.7, 0.0]).is_err());
+        assert!(six.add([0.0, 0.1, 0.5]).is_err());
+        assert!(!(0..6).any(|face| six.has(face)));
+    }
+}
@@ -1,23 +1,29 @@
 use std::{
     collections::HashMap,
     fmt::Display,
-    net::{SocketAddr, UdpSocket},
-    sync::mpsc,
+    net::SocketAddr,
+    sync::{mpsc, Arc},
     time::{Duration, Instant},
 };
 
 use itertools::Itertools;
 use nalgebra::{UnitQuaternion, Vector3};
-use protocol::deku::{DekuContainerRead, DekuContainerWrite};
 use protocol::PacketType;
+use serde::{Deserialize, Serialize};
 
 use super::{
-    imu::{Imu, JoyconAxisData},
+    bias::GyroBias,
+    connection::{discovery_address, Connection, Event, Queued},
+    imu::{AngleMeasure, Imu, JoyconAxisData},
+    mapping::ButtonMapper,
+    osc::{OscSender, VMC_STATUS_INTERVAL},
+    tap::TapDetector,
+    temperature::TemperatureCompensation,
     JoyconDesign,
 };
-use crate::settings;
+use crate::{api, settings, settings::ButtonAction};
 
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum Battery {
     Empty,
     Critical,
@@ -25,17 +31,57 @@ pub enum Battery {
     Medium,
     Full,
 }
+impl Battery {
+    /// Rough charge fraction for each level the controllers report.
+    pub fn level(self) -> f32 {
+        match self {
+            Battery::Empty => 0.0,
+            Battery::Critical => 0.1,
+            Battery::Low => 0.35,
+            Battery::Medium => 0.6,
+            Battery::Full => 1.0,
+        }
+    }
+    /// Approximate li-ion cell voltage matching `level`.
+    pub fn voltage(self) -> f32 {
+        match self {
+            Battery::Empty => 3.3,
+            Battery::Critical => 3.6,
+            Battery::Low => 3.7,
+            Battery::Medium => 3.8,
+            Battery::Full => 4.1,
+        }
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Status {
+    /// Roll, pitch and yaw in degrees of the tracker, with the mounting and the local reset.
     pub rotation: (f64, f64, f64),
+    /// Rotation quaternion (w, x, y, z) before the mounting and the local reset, for finding the
+    /// mounting.
+    pub raw_quat: [f64; 4],
     pub design: JoyconDesign,
     pub serial_number: String,
     pub battery: Battery,
     pub status: DeviceStatus,
+    pub packets_sent: u64,
+    /// Gyro bias in radians/s.
+    pub gyro_bias: [f64; 3],
+    pub bias_converged: bool,
+    /// True when the device has been lying still for a while.
+    pub still: bool,
+    /// Recent average acceleration in G.
+    pub acc_average: [f64; 3],
+    /// IMU temperature in celsius, if the backend can read it.
+    pub temperature: Option<f64>,
+    /// True when the gyro drift from the temperature is being removed.
+    pub drift_compensated: bool,
+    /// Radians turned since `ChannelInfo::MeasureAngle` started measuring.
+    pub measured_angle: Option<f64>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum DeviceStatus {
     Healthy,
     LaggyIMU,
@@ -56,28 +102,73 @@ impl Display for DeviceStatus {
 
 struct Device {
     imu: Imu,
+    bias: GyroBias,
+    temperature: TemperatureCompensation,
     design: JoyconDesign,
     send_id: u8,
     battery: Battery,
     status: DeviceStatus,
     imu_times: Vec<Instant>,
+    packets_sent: u64,
+    /// Packets waiting in a bundle, counted as sent once the bundle is.
+    packets_bundled: u64,
+    /// Set when the device is emulated as its own tracker board.
+    connection: Option<Connection>,
+    measure: Option<AngleMeasure>,
+    /// Leaves out the accelerometer calibration, while a new one is measured.
+    raw_accel: bool,
+    buttons: ButtonMapper,
+    taps: TapDetector,
+    /// The last local reset, and the IMU rotation it was made at.
+    reset: Option<(ResetKind, UnitQuaternion<f64>)>,
+    /// The server was asked for the same reset, so the rotation sent to it is left alone.
+    server_resets: bool,
 }
 
 impl Device {
-    pub fn handshake(&self, socket: &UdpSocket, address: &SocketAddr) {
+    fn send(&mut self, shared: &mut Connection, packet: PacketType) {
+        if self.connection.as_mut().unwrap_or(shared).send(packet) {
+            self.packets_sent += 1;
+        }
+    }
+    fn queue(&mut self, shared: &mut Connection, packet: PacketType) {
+        match self.connection.as_mut().unwrap_or(shared).queue(packet) {
+            Queued::Sent => self.packets_sent += 1,
+            Queued::Bundled => self.packets_bundled += 1,
+            Queued::Dropped => {}
+        }
+    }
+    /// Rotation put in front of the tracker rotation to undo the local reset.
+    fn reset_rotation(&self, mounting: UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+        self.reset
+            .map_or_else(UnitQuaternion::identity, |(kind, at)| {
+                kind.undo(at * mounting)
+            })
+    }
+    /// Rotation of the tracker with the mounting and the local reset.
+    fn tracker_rotation(&self, mounting: UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+        self.reset_rotation(mounting) * self.imu.rotation * mounting
+    }
+    /// Rotation sent to the server, which resets it itself when it was sent the reset.
+    fn server_rotation(&self, mounting: UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+        if self.server_resets {
+            self.imu.rotation * mounting
+        } else {
+            self.tracker_rotation(mounting)
+        }
+    }
+    pub fn handshake(&mut self, shared: &mut Connection) {
         let sensor_info = PacketType::SensorInfo {
             packet_id: 0,
             sensor_id: self.send_id,
             sensor_status: 1,
             sensor_type: 0,
         };
-        socket
-            .send_to(&sensor_info.to_bytes().unwrap(), address)
-            .unwrap();
+        self.send(shared, sensor_info);
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelData {
     pub serial_number: String,
     pub info: ChannelInfo,
@@ -91,87 +182,225 @@ impl ChannelData {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChannelInfo {
     Connected(JoyconDesign),
     ImuData([JoyconAxisData; 3]),
     Battery(Battery),
+    /// Yaw reset of all devices, and of the server when `send_reset` is on.
     Reset,
+    /// Full reset of all devices, and of the server when `send_reset` is on.
+    FullReset,
+    /// Resets only this device, inside Wrangler.
+    ResetDevice(ResetKind),
+    /// A button was pressed (true) or released (false).
+    Button(Button, bool),
+    /// IMU temperature in celsius, sent now and then by backends that can read it.
+    Temperature(f64),
+    /// Starts measuring how far the device turns around an axis, or stops with `None`.
+    MeasureAngle(Option<usize>),
+    /// Turns the accelerometer calibration off (true) or back on (false), to measure a new one.
+    RawAccel(bool),
     Disconnected,
+    /// Saves everything learned since the last save and stops, because Wrangler is exiting.
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Button {
+    A,
+    B,
+    X,
+    Y,
+    Up,
+    Down,
+    Left,
+    Right,
+    L,
+    R,
+    ZL,
+    ZR,
+    SL,
+    SR,
+    Minus,
+    Plus,
+    LStick,
+    RStick,
+    Home,
+    Capture,
+}
+impl Button {
+    pub const ALL: [Button; 20] = [
+        Button::A,
+        Button::B,
+        Button::X,
+        Button::Y,
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+        Button::L,
+        Button::R,
+        Button::ZL,
+        Button::ZR,
+        Button::SL,
+        Button::SR,
+        Button::Minus,
+        Button::Plus,
+        Button::LStick,
+        Button::RStick,
+        Button::Home,
+        Button::Capture,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Button::A => "a",
+            Button::B => "b",
+            Button::X => "x",
+            Button::Y => "y",
+            Button::Up => "up",
+            Button::Down => "down",
+            Button::Left => "left",
+            Button::Right => "right",
+            Button::L => "l",
+            Button::R => "r",
+            Button::ZL => "zl",
+            Button::ZR => "zr",
+            Button::SL => "sl",
+            Button::SR => "sr",
+            Button::Minus => "minus",
+            Button::Plus => "plus",
+            Button::LStick => "lstick",
+            Button::RStick => "rstick",
+            Button::Home => "home",
+            Button::Capture => "capture",
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-struct Xyz {
-    x: f64,
-    y: f64,
-    z: f64,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResetKind {
+    /// Turns the tracker around the vertical axis so that it faces forward.
+    Yaw,
+    /// Makes the current rotation of the tracker the starting rotation.
+    Full,
+}
+impl ResetKind {
+    /// Rotation that undoes `tracker`, only around the vertical axis for a yaw reset.
+    fn undo(self, tracker: UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+        match self {
+            ResetKind::Yaw => {
+                let q = tracker.quaternion();
+                UnitQuaternion::from_axis_angle(&Vector3::z_axis(), -2.0 * q.k.atan2(q.w))
+            }
+            ResetKind::Full => tracker.inverse(),
+        }
+    }
+    /// Type of the `UserAction` packet that asks the server for the same reset.
+    fn user_action(self) -> u8 {
+        match self {
+            ResetKind::Yaw => RESET_YAW,
+            ResetKind::Full => RESET_FULL,
+        }
+    }
 }
 
+/// Types of `UserAction` packets.
+const RESET_FULL: u8 = 2;
+const RESET_YAW: u8 = 3;
+const RESET_MOUNTING: u8 = 4;
+const PAUSE_TRACKING: u8 = 21;
+
+/// Acceleration without gravity, in the frame of the tracker.
 fn calc_acceleration(
     rotation: UnitQuaternion<f64>,
     axisdata: &JoyconAxisData,
-    rad_rotation: f64,
-) -> Xyz {
-    let a = rotation.coords;
-    let (x, y, z, w) = (a.x, a.y, a.z, a.w);
-    let gravity = [
-        2.0 * ((-x) * (-z) - w * y),
-        -2.0 * (w * (-x) + y * (-z)),
-        w * w - x * x - y * y + z * z,
-    ];
-    let vector = Xyz {
-        x: axisdata.accel_x - gravity[0],
-        y: axisdata.accel_y - gravity[1],
-        z: axisdata.accel_z - gravity[2],
-    };
-
-    let rad_rotation = -rad_rotation;
-    Xyz {
-        x: vector.x * rad_rotation.cos() - vector.y * rad_rotation.sin(),
-        y: vector.x * rad_rotation.sin() + vector.y * rad_rotation.cos(),
-        z: vector.z,
-    }
+    mounting: UnitQuaternion<f64>,
+) -> Vector3<f64> {
+    let gravity = rotation.inverse_transform_vector(&Vector3::z());
+    let acc = Vector3::new(axisdata.accel_x, axisdata.accel_y, axisdata.accel_z);
+    mounting.inverse_transform_vector(&(acc - gravity))
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize)]
 pub enum ServerStatus {
+    /// Sending failed, and is retried after a while.
+    NetworkError,
     #[default]
     Disconnected,
     Unknown,
     Connected,
 }
 
+/// Gives each controller its own MAC address when it is emulated as a separate tracker.
+fn device_mac(emulated_mac: [u8; 6], serial_number: &str) -> [u8; 6] {
+    // FNV-1a, since the server remembers trackers by MAC it has to stay the same between versions.
+    let hash = serial_number.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    let mut mac = emulated_mac;
+    for (m, h) in mac[2..].iter_mut().zip(hash.to_be_bytes()) {
+        *m ^= h;
+    }
+    mac
+}
+
 pub struct Communication {
     receive: mpsc::Receiver<ChannelData>,
     status_tx: mpsc::Sender<Vec<Status>>,
     server_tx: mpsc::Sender<ServerStatus>,
+    discovered_tx: mpsc::Sender<SocketAddr>,
+    api: Arc<api::Shared>,
     settings: settings::Handler,
 
     devices: HashMap<String, Device>,
 
     use_keep_ids: bool,
-    socket: UdpSocket,
+    separate_trackers: bool,
+    discover_server: bool,
     address: SocketAddr,
-    connected: ServerStatus,
-    last_handshake: Instant,
-    last_ping: Instant,
+    /// Board that all devices are sensors on, unless every device has its own connection.
+    shared: Connection,
+    server_status: ServerStatus,
     last_reset: Instant,
+    osc: OscSender,
+    started: Instant,
+    last_vmc_status: Instant,
+    /// Set by `ChannelInfo::Shutdown`, ends the main loop.
+    stopped: bool,
 }
 impl Communication {
     pub fn start(
         receive: mpsc::Receiver<ChannelData>,
         status_tx: mpsc::Sender<Vec<Status>>,
         server_tx: mpsc::Sender<ServerStatus>,
+        discovered_tx: mpsc::Sender<SocketAddr>,
+        api: Arc<api::Shared>,
         settings: settings::Handler,
     ) {
+        Self::new(receive, status_tx, server_tx, discovered_tx, api, settings).main_loop();
+    }
+
+    fn new(
+        receive: mpsc::Receiver<ChannelData>,
+        status_tx: mpsc::Sender<Vec<Status>>,
+        server_tx: mpsc::Sender<ServerStatus>,
+        discovered_tx: mpsc::Sender<SocketAddr>,
+        api: Arc<api::Shared>,
+        settings: settings::Handler,
+    ) -> Self {
         let addrs = [
             SocketAddr::from(([0, 0, 0, 0], 47589)),
             SocketAddr::from(([0, 0, 0, 0], 0)),
         ];
-        let socket = UdpSocket::bind(&addrs[..]).unwrap();
-        socket.set_nonblocking(true).ok();
         let address = { settings.load().get_socket_address() };
         let use_keep_ids = { settings.load().keep_ids };
+        let separate_trackers = { settings.load().separate_trackers };
+        let discover_server = { settings.load().discover_server };
+        let mut shared = Connection::bind(&addrs, address, settings.load().emulated_mac);
+        if discover_server && !separate_trackers {
+            shared.start_discovery(discovery_address());
+        }
 
         server_tx.send(ServerStatus::Disconnected).ok();
 
@@ -179,43 +408,43 @@ impl Communication {
             receive,
             status_tx,
             server_tx,
+            discovered_tx,
+            api,
             settings,
             devices: HashMap::new(),
             use_keep_ids,
-            socket,
+            separate_trackers,
+            discover_server,
             address,
-            connected: ServerStatus::Disconnected,
-            last_handshake: Instant::now().checked_sub(Duration::from_secs(60)).unwrap(),
-            last_ping: Instant::now(),
+            shared,
+            server_status: ServerStatus::Disconnected,
             last_reset: Instant::now(),
+            osc: OscSender::default(),
+            started: Instant::now(),
+            last_vmc_status: Instant::now(),
+            stopped: false,
         }
-        .main_loop();
-    }
-
-    fn send_handshake(&self) {
-        let handshake = PacketType::Handshake {
-            packet_id: 0,
-            board: 0,
-            imu: 0,
-            mcu_type: 0,
-            imu_info: (0, 0, 0),
-            build: 9,
-            firmware: "slimevr-wrangler".to_string().into(),
-            mac_address: self.settings.load().emulated_mac,
-        };
-        self.socket
-            .send_to(&handshake.to_bytes().unwrap(), self.address)
-            .unwrap();
     }
 
-    fn send_reset(&self) {
-        let handshake = PacketType::UserAction {
-            packet_id: 0,
-            typ: 3,
-        };
-        self.socket
-            .send_to(&handshake.to_bytes().unwrap(), self.address)
-            .unwrap();
+    /// The emptiest battery for the shared board, or each device's own battery.
+    fn send_battery(&mut self) {
+        if self.separate_trackers {
+            for device in self.devices.values_mut() {
+                if let Some(connection) = device.connection.as_mut() {
+                    connection.send_battery(device.battery);
+                }
+            }
+            return;
+        }
+        if let Some(battery) = self
+            .devices
+            .values()
+            .filter(|d| d.status != DeviceStatus::Disconnected)
+            .map(|d| d.battery)
+            .min()
+        {
+            self.shared.send_battery(battery);
+        }
     }
 
     fn parse_message(&mut self, msg: ChannelData) {
@@ -224,87 +453,257 @@ impl Communication {
             ChannelInfo::Connected(design) => {
                 if self.devices.contains_key(&sn) {
                     let device = self.devices.get_mut(&sn).unwrap();
-                    device.imu = Imu::new();
+                    device.imu = Imu::new(self.settings.load().joycon_filter_get(&sn));
                     device.imu_times = vec![];
+                    device.packets_sent = 0;
+                    device.packets_bundled = 0;
+                    device.buttons.release_all();
+                    device.reset = None;
                     return;
                 }
 
-                let send_id = if self.use_keep_ids {
-                    self.settings.joycon_keep_id(sn.clone())
+                let (send_id, connection) = if self.separate_trackers {
+                    let mac = device_mac(self.settings.load().emulated_mac, &sn);
+                    let local = [SocketAddr::from(([0, 0, 0, 0], 0))];
+                    let mut connection = Connection::bind(&local, self.address, mac);
+                    if self.discover_server {
+                        connection.start_discovery(discovery_address());
+                    }
+                    (0, Some(connection))
+                } else if self.use_keep_ids {
+                    (self.settings.joycon_keep_id(sn.clone()), None)
                 } else {
-                    self.devices.len() as _
+                    (self.devices.len() as _, None)
                 };
-                let device = Device {
-                    imu: Imu::new(),
+                let mut device = Device {
+                    imu: Imu::new(self.settings.load().joycon_filter_get(&sn)),
+                    bias: GyroBias::new(self.settings.load().joycon_gyro_bias_get(&sn)),
+                    temperature: TemperatureCompensation::new(
+                        self.settings.load().joycon_temperature_drift_get(&sn),
+                    ),
                     design,
                     send_id,
                     battery: Battery::Full,
                     status: DeviceStatus::NoIMU,
                     imu_times: vec![],
+                    packets_sent: 0,
+                    packets_bundled: 0,
+                    connection,
+                    measure: None,
+                    raw_accel: false,
+                    buttons: ButtonMapper::default(),
+                    taps: TapDetector::default(),
+                    reset: None,
+                    server_resets: false,
                 };
 
-                device.handshake(&self.socket, &self.address);
+                // Devices with their own connection are announced once it has handshaked.
+                if device.connection.is_none() {
+                    device.handshake(&mut self.shared);
+                }
                 self.devices.insert(sn, device);
             }
-            ChannelInfo::ImuData(imu_data) => {
+            ChannelInfo::ImuData(mut imu_data) => {
+                let settings = self.settings.load();
+                let mut tapped = vec![];
                 if let Some(device) = self.devices.get_mut(&sn) {
+                    let filter_settings = settings.joycon_filter_get(&sn);
+                    if device.imu.filter_settings != filter_settings {
+                        device.imu = Imu::new(filter_settings);
+                        device.reset = None;
+                    }
+                    if !device.raw_accel {
+                        let accel_calibration = settings.joycon_accel_calibration_get(&sn);
+                        for frame in &mut imu_data {
+                            [frame.accel_x, frame.accel_y, frame.accel_z] = accel_calibration
+                                .apply([frame.accel_x, frame.accel_y, frame.accel_z]);
+                        }
+                    }
                     for frame in imu_data {
+                        if settings.taps.enabled {
+                            if let Some(count) = device.taps.update(&frame, &settings.taps) {
+                                tapped.extend(
+                                    settings.taps.mappings.iter().filter(|m| m.taps == count),
+                                );
+                            }
+                        }
+                        let frame = device.temperature.update(frame);
+                        let frame = device.bias.update(frame);
+                        if let Some(measure) = &mut device.measure {
+                            measure.update(&frame);
+                        }
                         device.imu.update(frame);
                     }
+                    if device.bias.still() && device.bias.converged() {
+                        device
+                            .temperature
+                            .record(imu_data[2].time, device.bias.bias.into());
+                    }
                     device.imu_times.push(Instant::now());
 
-                    let joycon_rotation = self.settings.load().joycon_rotation_get(&sn);
-                    let rad_rotation = (joycon_rotation as f64).to_radians();
-                    let rotated_quat = if joycon_rotation > 0 {
-                        device.imu.rotation
-                            * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), rad_rotation)
-                    } else {
-                        device.imu.rotation
-                    };
+                    let mounting = settings.joycon_mounting_get(&sn);
+                    let rotated_quat = device.tracker_rotation(mounting);
 
                     let rotation_packet = PacketType::RotationData {
                         packet_id: 0,
                         sensor_id: device.send_id,
                         data_type: 1,
-                        quat: (*rotated_quat).into(),
+                        quat: (*device.server_rotation(mounting)).into(),
                         calibration_info: 0,
                     };
-                    self.socket
-                        .send_to(&rotation_packet.to_bytes().unwrap(), self.address)
-                        .unwrap();
+                    device.queue(&mut self.shared, rotation_packet);
 
-                    let acc = calc_acceleration(device.imu.rotation, &imu_data[2], rad_rotation);
+                    let acc = calc_acceleration(device.imu.rotation, &imu_data[2], mounting);
                     let acceleration_packet = PacketType::Acceleration {
                         packet_id: 0,
                         vector: (acc.x as f32, acc.y as f32, acc.z as f32),
                         sensor_id: Some(device.send_id),
                     };
-                    self.socket
-                        .send_to(&acceleration_packet.to_bytes().unwrap(), self.address)
-                        .unwrap();
+                    device.queue(&mut self.shared, acceleration_packet);
+
+                    if settings.osc.enabled {
+                        self.osc.send_rotation(&settings.osc, &sn, rotated_quat);
+                    }
+                    if settings.vmc.enabled {
+                        let name = settings.joycon_vmc_name_get(&sn);
+                        self.osc.send_vmc_tracker(&settings.vmc, name, rotated_quat);
+                    }
+                }
+                for mapping in tapped {
+                    self.run_action(&sn, mapping.action, &mapping.osc_address);
                 }
             }
             ChannelInfo::Battery(battery) => {
                 if let Some(device) = self.devices.get_mut(&sn) {
                     device.battery = battery;
+                    self.send_battery();
+                }
+            }
+            ChannelInfo::Reset => self.reset(&sn, ResetKind::Yaw),
+            ChannelInfo::FullReset => self.reset(&sn, ResetKind::Full),
+            ChannelInfo::ResetDevice(kind) => {
+                if let Some(device) = self.devices.get_mut(&sn) {
+                    device.reset = Some((kind, device.imu.rotation));
+                    device.server_resets = false;
+                }
+            }
+            ChannelInfo::Button(button, pressed) => {
+                let settings = self.settings.load();
+                if settings.osc.enabled {
+                    self.osc.send_button(&settings.osc, &sn, button, pressed);
+                }
+                if let Some(device) = self.devices.get_mut(&sn) {
+                    device.buttons.button(button, pressed, Instant::now());
+                }
+            }
+            ChannelInfo::Temperature(temperature) => {
+                if let Some(device) = self.devices.get_mut(&sn) {
+                    device.temperature.temperature = Some(temperature);
                 }
             }
-            ChannelInfo::Reset => {
-                if self.settings.load().send_reset && self.last_reset.elapsed().as_secs() >= 2 {
-                    self.last_reset = Instant::now();
-                    self.send_reset();
+            ChannelInfo::MeasureAngle(axis) => {
+                if let Some(device) = self.devices.get_mut(&sn) {
+                    device.measure = axis.map(AngleMeasure::new);
+                }
+            }
+            ChannelInfo::RawAccel(raw) => {
+                if let Some(device) = self.devices.get_mut(&sn) {
+                    device.raw_accel = raw;
                 }
             }
             ChannelInfo::Disconnected => {
                 if let Some(device) = self.devices.get_mut(&sn) {
                     device.imu_times = vec![];
+                    device.buttons.release_all();
                     device.status = DeviceStatus::Disconnected;
+                    self.send_battery();
                 }
             }
+            ChannelInfo::Shutdown => {
+                self.save_learned(true);
+                self.stopped = true;
+            }
+        }
+    }
+
+    /// Resets every device inside Wrangler, and the server through the device `sn` when
+    /// `send_reset` is on.
+    fn reset(&mut self, sn: &str, kind: ResetKind) {
+        let send_reset = self.settings.load().send_reset;
+        for device in self.devices.values_mut() {
+            device.reset = Some((kind, device.imu.rotation));
+            device.server_resets = send_reset;
+        }
+        if send_reset && self.last_reset.elapsed().as_secs() >= 2 {
+            self.last_reset = Instant::now();
+            self.send_user_action(sn, kind.user_action());
+        }
+    }
+
+    fn send_user_action(&mut self, sn: &str, typ: u8) {
+        if let Some(device) = self.devices.get_mut(sn) {
+            device.send(
+                &mut self.shared,
+                PacketType::UserAction { packet_id: 0, typ },
+            );
+        }
+    }
+
+    /// Runs the button mappings that are due on each device.
+    fn update_buttons(&mut self) {
+        let settings = self.settings.load();
+        let now = Instant::now();
+        let mut actions = vec![];
+        for (serial_number, device) in &mut self.devices {
+            for i in device.buttons.update(&settings.button_mappings, now) {
+                actions.push((serial_number.clone(), &settings.button_mappings[i]));
+            }
+        }
+        for (sn, mapping) in actions {
+            self.run_action(&sn, mapping.action, &mapping.osc_address);
+        }
+    }
+
+    /// Runs an action from a button or tap mapping on the device `sn`.
+    fn run_action(&mut self, sn: &str, action: ButtonAction, osc_address: &str) {
+        match action {
+            ButtonAction::QuickReset => self.reset(sn, ResetKind::Yaw),
+            ButtonAction::FullReset => self.reset(sn, ResetKind::Full),
+            ButtonAction::MountingReset => self.send_user_action(sn, RESET_MOUNTING),
+            ButtonAction::PauseTracking => self.send_user_action(sn, PAUSE_TRACKING),
+            ButtonAction::Osc => {
+                let settings = self.settings.load();
+                self.osc.send_event(&settings.osc, osc_address, sn);
+            }
+        }
+    }
+
+    /// Saves the learned bias and temperature drift now and then, or whatever is left when exiting.
+    fn save_learned(&mut self, exiting: bool) {
+        for (serial_number, device) in &mut self.devices {
+            let bias = if exiting {
+                device.bias.take_changed()
+            } else {
+                device.bias.take_unsaved()
+            };
+            if let Some(bias) = bias {
+                self.settings
+                    .change(|ws| ws.joycon_gyro_bias_set(serial_number.clone(), bias));
+            }
+            let drift = if exiting {
+                device.temperature.take_changed()
+            } else {
+                device.temperature.take_unsaved()
+            };
+            if let Some(drift) = drift {
+                self.settings
+                    .change(|ws| ws.joycon_temperature_drift_set(serial_number.clone(), drift));
+            }
         }
     }
 
     fn update_statuses(&mut self) {
+        self.save_learned(false);
         let discard_before = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
         for device in self.devices.values_mut() {
             device.imu_times.retain(|t| t > &discard_before);
@@ -324,6 +723,128 @@ impl Communication {
         }
     }
 
+    fn connections(&mut self) -> impl Iterator<Item = &mut Connection> {
+        std::iter::once(&mut self.shared).chain(
+            self.devices
+                .values_mut()
+                .filter_map(|d| d.connection.as_mut()),
+        )
+    }
+
+    /// Sends the bundles of all connections, and counts the packets in them once sent.
+    fn flush(&mut self) {
+        let shared_sent = self.shared.flush();
+        for device in self.devices.values_mut() {
+            let sent = match device.connection.as_mut() {
+                Some(connection) => connection.flush(),
+                None => shared_sent,
+            };
+            if sent {
+                device.packets_sent += device.packets_bundled;
+            }
+            device.packets_bundled = 0;
+        }
+    }
+
+    /// Picks up settings that were changed while running.
+    fn apply_settings(&mut self) {
+        let (bundle_packets, address, discover_server, use_keep_ids) = {
+            let settings = self.settings.load();
+            (
+                settings.bundle_packets,
+                settings.get_socket_address(),
+                settings.discover_server,
+                settings.keep_ids,
+            )
+        };
+        for connection in self.connections() {
+            connection.want_bundles = bundle_packets;
+        }
+
+        if address != self.address || discover_server != self.discover_server {
+            self.address = address;
+            self.discover_server = discover_server;
+            for connection in self.connections() {
+                if discover_server {
+                    connection.start_discovery(discovery_address());
+                } else {
+                    connection.set_address(address);
+                }
+            }
+        }
+
+        if use_keep_ids != self.use_keep_ids {
+            self.use_keep_ids = use_keep_ids;
+            if !self.separate_trackers {
+                let devices = self.devices.iter_mut().sorted_by_key(|(_, d)| d.send_id);
+                for (id, (serial_number, device)) in devices.enumerate() {
+                    device.send_id = if use_keep_ids {
+                        self.settings.joycon_keep_id(serial_number.clone())
+                    } else {
+                        id as _
+                    };
+                }
+                // Announces the devices again with their new ids.
+                self.shared.reconnect();
+            }
+        }
+    }
+
+    /// Keeps all connections to the server alive, and announces the devices on them.
+    fn update_connections(&mut self, buf: &mut [u8]) {
+        let mut connected = false;
+        if self.separate_trackers {
+            for device in self.devices.values_mut() {
+                let Some(connection) = device.connection.as_mut() else {
+                    continue;
+                };
+                for event in connection.update(buf) {
+                    match event {
+                        Event::HandshakeSent => device.handshake(&mut self.shared),
+                        Event::Connected => connected = true,
+                        Event::Discovered(address) => {
+                            self.discovered_tx.send(address).ok();
+                        }
+                    }
+                }
+            }
+        } else {
+            for event in self.shared.update(buf) {
+                match event {
+                    Event::HandshakeSent => {
+                        for device in self.devices.values_mut().sorted_by_key(|d| d.send_id) {
+                            device.handshake(&mut self.shared);
+                        }
+                    }
+                    Event::Connected => connected = true,
+                    Event::Discovered(address) => {
+                        self.discovered_tx.send(address).ok();
+                    }
+                }
+            }
+        }
+        if connected {
+            self.send_battery();
+        }
+
+        // With separate trackers, show the worst connection of them.
+        let server_status = if self.separate_trackers {
+            self.devices
+                .values()
+                .filter_map(|d| d.connection.as_ref())
+                .map(|c| c.status)
+                .min()
+                .unwrap_or_default()
+        } else {
+            self.shared.status
+        };
+        if server_status != self.server_status {
+            self.server_status = server_status;
+            self.server_tx.send(server_status).ok();
+            self.api.server.store(Arc::new(server_status));
+        }
+    }
+
     pub fn main_loop(&mut self) {
         let mut buf = [0; 512];
 
@@ -335,64 +856,321 @@ impl Communication {
 
         let mut last_ui_send = Instant::now();
 
-        loop {
-            if self.connected != ServerStatus::Connected
-                && self.last_handshake.elapsed().as_secs() >= 3
-            {
-                self.last_handshake = Instant::now();
-                self.send_handshake();
-                for device in self.devices.values().sorted_by_key(|d| d.send_id) {
-                    device.handshake(&self.socket, &self.address);
-                }
-            }
-            while let Ok(len) = self.socket.recv(&mut buf) {
-                if self.connected == ServerStatus::Disconnected {
-                    self.connected = ServerStatus::Unknown;
-                    self.server_tx.send(self.connected).ok();
-                }
-                let b = PacketType::from_bytes((&buf, 0));
-                match b {
-                    Ok((_, PacketType::Ping { id: _ })) => {
-                        self.last_ping = Instant::now();
-                        self.socket.send_to(&buf[0..len], self.address).unwrap();
-                    }
-                    Ok((_, PacketType::HandshakeResponse)) => {
-                        self.connected = ServerStatus::Connected;
-                        self.server_tx.send(self.connected).ok();
-                    }
-                    _ => {}
-                }
-            }
-            if self.connected != ServerStatus::Disconnected
-                && self.last_ping.elapsed().as_secs() >= 3
-            {
-                self.connected = ServerStatus::Disconnected;
-                self.server_tx.send(self.connected).ok();
-            }
+        while !self.stopped {
+            self.update_connections(&mut buf);
 
             let messages: Vec<_> = self.receive.try_iter().collect();
             if !messages.is_empty() || last_ui_send.elapsed().as_millis() > 100 {
+                self.apply_settings();
                 for msg in messages {
                     self.parse_message(msg);
                 }
+                self.update_buttons();
+                self.flush();
 
                 self.update_statuses();
 
-                last_ui_send = Instant::now();
-                let mut statuses = Vec::new();
-                for (serial_number, device) in &self.devices {
-                    statuses.push(Status {
-                        rotation: device.imu.euler_angles_deg(),
-                        design: device.design.clone(),
-                        serial_number: serial_number.clone(),
-                        battery: device.battery,
-                        status: device.status,
-                    });
+                let settings = self.settings.load();
+                if settings.vmc.enabled && self.last_vmc_status.elapsed() >= VMC_STATUS_INTERVAL {
+                    self.last_vmc_status = Instant::now();
+                    let time = self.started.elapsed().as_secs_f32();
+                    self.osc.send_vmc_status(&settings.vmc, time);
                 }
+
+                last_ui_send = Instant::now();
+                let statuses = self.statuses();
+                self.api.devices.store(Arc::new(statuses.clone()));
                 self.status_tx.send(statuses).ok();
             } else {
                 light_sleeper.sleep(Duration::from_millis(2));
             }
         }
     }
+
+    fn statuses(&self) -> Vec<Status> {
+        let settings = self.settings.load();
+        self.devices
+            .iter()
+            .map(|(serial_number, device)| {
+                let mounting = settings.joycon_mounting_get(serial_number);
+                let ea = device.tracker_rotation(mounting).euler_angles();
+                Status {
+                    rotation: (ea.0.to_degrees(), ea.1.to_degrees(), ea.2.to_degrees()),
+                    raw_quat: {
+                        let q = device.imu.rotation.quaternion();
+                        [q.w, q.i, q.j, q.k]
+                    },
+                    design: device.design.clone(),
+                    serial_number: serial_number.clone(),
+                    battery: device.battery,
+                    status: device.status,
+                    packets_sent: device.packets_sent,
+                    gyro_bias: device.bias.bias.into(),
+                    bias_converged: device.bias.converged(),
+                    still: device.bias.still(),
+                    acc_average: device.bias.acc_average(),
+                    temperature: device.temperature.temperature,
+                    drift_compensated: device.temperature.compensating(),
+                    measured_angle: device.measure.as_ref().map(|m| m.angle),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f64::consts::FRAC_PI_2, net::UdpSocket};
+
+    use protocol::deku::DekuContainerRead;
+
+    use super::*;
+    use crate::{
+        joycon::{replay_integration::replay, JoyconDesignType},
+        settings::{AccelCalibration, FilterKind, WranglerSettings},
+    };
+
+    /// Communication that sends to the returned mock server, with the settings changed by `func`.
+    fn communication(
+        func: impl FnOnce(&mut WranglerSettings),
+    ) -> (Communication, mpsc::Sender<ChannelData>, UdpSocket) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let settings = settings::Handler::in_memory(|ws| {
+            ws.address = address;
+            func(ws);
+        });
+        let (tx, rx) = mpsc::channel();
+        let communication = Communication::new(
+            rx,
+            mpsc::channel().0,
+            mpsc::channel().0,
+            mpsc::channel().0,
+            Arc::default(),
+            settings,
+        );
+        (communication, tx, server)
+    }
+
+    /// Everything the mock server has received so far.
+    fn received(server: &UdpSocket) -> Vec<PacketType> {
+        let mut buf = [0; 512];
+        let mut packets = vec![];
+        while let Ok(len) = server.recv(&mut buf) {
+            if let Ok((_, packet)) = PacketType::from_bytes((&buf[..len], 0)) {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    fn connected(serial_number: &str) -> ChannelData {
+        ChannelData::new(
+            serial_number.into(),
+            ChannelInfo::Connected(JoyconDesign {
+                color: "#000000".into(),
+                design_type: JoyconDesignType::Left,
+            }),
+        )
+    }
+
+    /// Three samples lying still with the raw acceleration `acc`, starting at `time`.
+    fn lying(serial_number: &str, acc: [f64; 3], time: f64) -> ChannelData {
+        let frame = |i: usize| JoyconAxisData {
+            accel_x: acc[0],
+            accel_y: acc[1],
+            accel_z: acc[2],
+            gyro_x: 0.0,
+            gyro_y: 0.0,
+            gyro_z: 0.0,
+            time: time + i as f64 * 0.005,
+        };
+        ChannelData::new(
+            serial_number.into(),
+            ChannelInfo::ImuData([0, 1, 2].map(frame)),
+        )
+    }
+
+    #[test]
+    fn sends_calibrated_acceleration() {
+        let calibration = AccelCalibration {
+            offset: [0.1, -0.05, 0.2],
+            scale: [1.0, 1.0, 0.5],
+        };
+        let (mut communication, _, server) = communication(|ws| {
+            ws.joycon_accel_calibration_set("a".into(), calibration);
+            ws.joycon.get_mut("a").unwrap().filter.kind = FilterKind::Madgwick;
+        });
+        communication.parse_message(connected("a"));
+        // Exactly 1G up once calibrated, so nothing is left after removing gravity.
+        communication.parse_message(lying("a", [0.1, -0.05, 2.2], 1000.0));
+
+        let vectors: Vec<_> = received(&server)
+            .into_iter()
+            .filter_map(|packet| match packet {
+                PacketType::Acceleration { vector, .. } => Some(vector),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vectors.len(), 1);
+        let (x, y, z) = vectors[0];
+        assert!(
+            x.abs() < 1e-3 && y.abs() < 1e-3 && z.abs() < 1e-3,
+            "{:?}",
+            vectors[0]
+        );
+    }
+
+    #[test]
+    fn acceleration_without_gravity() {
+        let rotation = UnitQuaternion::from_euler_angles(0.4, -0.2, 1.0);
+        let mounting = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 1.5);
+        // Moving straight up while tilted.
+        let acc = rotation.inverse_transform_vector(&Vector3::new(0.0, 0.0, 1.5));
+        let frame = JoyconAxisData {
+            accel_x: acc.x,
+            accel_y: acc.y,
+            accel_z: acc.z,
+            gyro_x: 0.0,
+            gyro_y: 0.0,
+            gyro_z: 0.0,
+            time: 0.0,
+        };
+        let result = calc_acceleration(rotation, &frame, mounting);
+        let expected = (rotation * mounting).inverse_transform_vector(&Vector3::new(0.0, 0.0, 0.5));
+        assert!((result - expected).norm() < 1e-9);
+    }
+
+    #[test]
+    fn reset_needles_show_reset_tracker() {
+        let mounting = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 1.2);
+        let (mut communication, _, _server) = communication(|ws| {
+            ws.joycon_mounting_set("a".into(), mounting);
+            ws.joycon.get_mut("a").unwrap().filter.kind = FilterKind::Madgwick;
+        });
+        communication.parse_message(connected("a"));
+        communication.parse_message(lying("a", [0.0, 0.0, 1.0], 1000.0));
+        communication.parse_message(ChannelData::new(
+            "a".into(),
+            ChannelInfo::ResetDevice(ResetKind::Full),
+        ));
+        let (roll, pitch, yaw) = communication.statuses()[0].rotation;
+        assert!(roll.abs() < 1e-6 && pitch.abs() < 1e-6 && yaw.abs() < 1e-6);
+    }
+
+    #[test]
+    fn server_reset_is_not_stacked() {
+        for send_reset in [true, false] {
+            let (mut communication, _, server) = communication(|ws| {
+                ws.send_reset = send_reset;
+                ws.joycon.entry("a".into()).or_default().filter.kind = FilterKind::Madgwick;
+            });
+            communication.last_reset = Instant::now() - Duration::from_secs(5);
+            communication.parse_message(connected("a"));
+            communication.parse_message(ChannelData::new(
+                "a".into(),
+                ChannelInfo::ImuData([0, 1, 2].map(|i| JoyconAxisData {
+                    accel_x: 0.0,
+                    accel_y: 0.0,
+                    accel_z: 1.0,
+                    gyro_x: 0.0,
+                    gyro_y: 0.0,
+                    gyro_z: 10.0,
+                    time: 1000.0 + i as f64 * 0.005,
+                })),
+            ));
+            communication.parse_message(ChannelData::new("a".into(), ChannelInfo::FullReset));
+            received(&server);
+            communication.parse_message(lying("a", [0.0, 0.0, 1.0], 1000.015));
+
+            let (roll, pitch, yaw) = communication.statuses()[0].rotation;
+            assert!(roll.abs() < 1e-6 && pitch.abs() < 1e-6 && yaw.abs() < 1e-6);
+            let sent = received(&server)
+                .into_iter()
+                .find_map(|packet| match packet {
+                    PacketType::RotationData { quat, .. } => Some(quat),
+                    _ => None,
+                })
+                .unwrap();
+            // The server gets the turned rotation to reset itself, or the reset one.
+            assert_eq!(sent.w.abs() < 0.999, send_reset, "{sent:?}");
+        }
+    }
+
+    #[test]
+    fn replays_recorded_turn() {
+        // Lies flat, turns 1.5 radians around z in one second and lies still again.
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/joycon/testdata/turn.jsonl"
+        );
+        let mounting = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), FRAC_PI_2);
+        let (mut communication, tx, server) = communication(|ws| {
+            ws.joycon_mounting_set("recorded".into(), mounting);
+            ws.joycon_accel_calibration_set(
+                "recorded".into(),
+                AccelCalibration {
+                    offset: [0.0, 0.0, 0.02],
+                    scale: [1.0, 1.0, 1.02],
+                },
+            );
+            ws.joycon.get_mut("recorded").unwrap().filter.kind = FilterKind::Madgwick;
+        });
+        replay(tx, path.into(), 0.0);
+        let messages: Vec<_> = communication.receive.try_iter().collect();
+        for msg in messages {
+            communication.parse_message(msg);
+        }
+
+        let sent: Vec<_> = received(&server)
+            .into_iter()
+            .filter_map(|packet| match packet {
+                PacketType::RotationData { quat, .. } => {
+                    Some(UnitQuaternion::from_quaternion(quat.into()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent.len(), 106);
+        let expected = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), 1.5) * mounting;
+        let last = sent.last().unwrap();
+        assert!(last.angle_to(&expected) < 0.02, "{:?}", last.euler_angles());
+    }
+
+    #[test]
+    fn saves_bias_on_shutdown() {
+        let (mut communication, _, _server) = communication(|_| {});
+        communication.parse_message(connected("a"));
+        // Lies still for 5 seconds, too short to be saved while it keeps lying there.
+        for i in 0..333 {
+            let mut data = lying("a", [0.0, 0.0, 1.0], i as f64 * 0.015);
+            if let ChannelInfo::ImuData(frames) = &mut data.info {
+                for frame in frames {
+                    frame.gyro_x = 0.02;
+                }
+            }
+            communication.parse_message(data);
+            communication.update_statuses();
+        }
+        assert_eq!(
+            communication.settings.load().joycon_gyro_bias_get("a"),
+            [0.0; 3]
+        );
+
+        communication.parse_message(ChannelData::new(String::new(), ChannelInfo::Shutdown));
+        communication.main_loop();
+        let bias = communication.settings.load().joycon_gyro_bias_get("a");
+        assert!(bias[0] > 0.01, "{bias:?}");
+    }
+
+    #[test]
+    fn resets_keep_tilt() {
+        let tilt = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 0.3);
+        let tracker = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), 0.7) * tilt;
+        assert!((ResetKind::Yaw.undo(tracker) * tracker).angle_to(&tilt) < 1e-9);
+        assert!((ResetKind::Full.undo(tracker) * tracker).angle() < 1e-9);
+    }
 }
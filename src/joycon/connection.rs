@@ -0,0 +1,508 @@
+use std::{
+    io,
+    mem::take,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use protocol::{
+    deku::{DekuContainerRead, DekuContainerWrite},
+    has_feature, server_features, BundledPacket, PacketType, ServerPacket,
+};
+
+use super::{Battery, ServerStatus};
+
+// The SlimeVR Server reads datagrams into a 512 byte buffer.
+const MAX_BUNDLE_SIZE: usize = 512;
+// Packet type + packet number.
+const BUNDLE_HEADER_SIZE: usize = 12;
+
+// Wrangler has none of the optional tracker features, but announcing an empty set makes the
+// server answer with its own feature flags.
+const WRANGLER_FEATURES: [u8; 0] = [];
+const FEATURE_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+const FEATURE_REQUEST_ATTEMPTS: u32 = 10;
+
+// Sending is paused for a while after network errors, doubling each time it fails again.
+const MIN_ERROR_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Handshakes are broadcast here when looking for servers, the SlimeVR Server listens on port 6969.
+pub fn discovery_address() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::BROADCAST, 6969))
+}
+
+/// Features the server announced in reply to our feature flags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerFeatures {
+    #[default]
+    Unknown,
+    /// The server never answered, so it predates feature flags.
+    Unsupported,
+    Known(Vec<u8>),
+}
+impl ServerFeatures {
+    pub fn has(&self, bit: usize) -> bool {
+        match self {
+            ServerFeatures::Known(flags) => has_feature(flags, bit),
+            ServerFeatures::Unknown | ServerFeatures::Unsupported => false,
+        }
+    }
+}
+
+/// What happened to a packet given to `Connection::queue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    Sent,
+    /// Held in the bundle until the next `flush`.
+    Bundled,
+    Dropped,
+}
+
+/// Things that happened on a connection, that the sensors on it need to react to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The board handshake was sent, so sensors have to be announced again.
+    HandshakeSent,
+    /// The server answered the handshake.
+    Connected,
+    /// A server answered a broadcast handshake.
+    Discovered(SocketAddr),
+}
+
+/// One emulated tracker board talking to the SlimeVR Server over its own UDP socket.
+/// Every packet is stamped with an increasing packet number, so the server can detect lost and
+/// reordered packets.
+pub struct Connection {
+    /// Bound on first use, and bound again after network errors.
+    socket: Option<UdpSocket>,
+    local: Vec<SocketAddr>,
+    pub address: SocketAddr,
+    mac: [u8; 6],
+    last_packet_id: u64,
+
+    pub status: ServerStatus,
+    last_handshake: Instant,
+    last_ping: Instant,
+
+    protocol_version: Option<u32>,
+    features: ServerFeatures,
+    feature_requests: u32,
+    last_feature_request: Instant,
+
+    /// Bundles are only used if the server also supports them.
+    pub want_bundles: bool,
+    bundle: Vec<BundledPacket>,
+    bundle_size: usize,
+    /// A bundle could not be sent since the last `flush`.
+    bundle_lost: bool,
+
+    sent_battery: Option<Battery>,
+
+    /// Where handshakes are broadcast to, when the server is found automatically.
+    discovery: Option<SocketAddr>,
+    searching: bool,
+
+    error_backoff: Duration,
+    retry_at: Instant,
+}
+impl Connection {
+    pub fn bind(local: &[SocketAddr], address: SocketAddr, mac: [u8; 6]) -> Self {
+        let mut connection = Self {
+            socket: None,
+            local: local.to_vec(),
+            address,
+            mac,
+            last_packet_id: 0,
+            status: ServerStatus::Disconnected,
+            last_handshake: Instant::now(),
+            last_ping: Instant::now(),
+            protocol_version: None,
+            features: ServerFeatures::Unknown,
+            feature_requests: 0,
+            last_feature_request: Instant::now(),
+            want_bundles: false,
+            bundle: vec![],
+            bundle_size: BUNDLE_HEADER_SIZE,
+            bundle_lost: false,
+            sent_battery: None,
+            discovery: None,
+            searching: false,
+            error_backoff: MIN_ERROR_BACKOFF,
+            retry_at: Instant::now(),
+        };
+        connection.reconnect();
+        connection
+    }
+
+    /// Handshakes again with the next update, and forgets what the server told us.
+    pub fn reconnect(&mut self) {
+        self.forget_server();
+        self.last_handshake = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+    }
+
+    /// Forgets what the server told us, the next handshake is sent when it is due.
+    fn forget_server(&mut self) {
+        self.status = ServerStatus::Disconnected;
+        self.last_ping = Instant::now();
+        self.features = ServerFeatures::Unknown;
+        self.bundle.clear();
+        self.bundle_size = BUNDLE_HEADER_SIZE;
+    }
+
+    /// Stops any discovery and switches to a new server.
+    pub fn set_address(&mut self, address: SocketAddr) {
+        if let Some(socket) = &self.socket {
+            socket.set_broadcast(false).ok();
+        }
+        self.discovery = None;
+        self.searching = false;
+        self.address = address;
+        self.reconnect();
+    }
+
+    /// Broadcasts handshakes to `target` and uses the first server that answers.
+    pub fn start_discovery(&mut self, target: SocketAddr) {
+        if let Some(socket) = &self.socket {
+            socket.set_broadcast(true).ok();
+        }
+        self.discovery = Some(target);
+        self.address = target;
+        self.searching = true;
+        self.reconnect();
+    }
+
+    fn socket(&mut self) -> io::Result<&UdpSocket> {
+        if self.socket.is_none() {
+            let socket = UdpSocket::bind(&self.local[..])?;
+            socket.set_nonblocking(true)?;
+            socket.set_broadcast(self.discovery.is_some())?;
+            self.socket = Some(socket);
+        }
+        Ok(self.socket.as_ref().unwrap())
+    }
+
+    fn backing_off(&self) -> bool {
+        self.status == ServerStatus::NetworkError && Instant::now() < self.retry_at
+    }
+
+    /// Sends data as is, returning whether it was sent. Errors pause sending with a growing
+    /// backoff, until a send works again.
+    fn send_raw(&mut self, data: &[u8]) -> bool {
+        if self.backing_off() {
+            return false;
+        }
+        let address = self.address;
+        match self
+            .socket()
+            .and_then(|socket| socket.send_to(data, address))
+        {
+            Ok(_) => {
+                if self.status == ServerStatus::NetworkError {
+                    println!("[INFO] Network to SlimeVR Server at {address} works again.");
+                    self.error_backoff = MIN_ERROR_BACKOFF;
+                    self.forget_server();
+                }
+                true
+            }
+            Err(e) => {
+                if self.status == ServerStatus::NetworkError {
+                    self.error_backoff = (self.error_backoff * 2).min(MAX_ERROR_BACKOFF);
+                } else {
+                    println!(
+                        "\x1b[0;31m[ERROR]\x1b[0m Could not send to SlimeVR Server at {address}: {e}"
+                    );
+                    self.status = ServerStatus::NetworkError;
+                }
+                self.retry_at = Instant::now() + self.error_backoff;
+                // The network may have changed, so the socket is bound again on the next try.
+                self.socket = None;
+                false
+            }
+        }
+    }
+
+    /// Sends the packet with the next packet number, returning whether it was sent. The number
+    /// is only used up by packets that were sent.
+    pub fn send(&mut self, mut packet: PacketType) -> bool {
+        if self.backing_off() {
+            return false;
+        }
+        let numbered = if let Some(packet_id) = packet.packet_id_mut() {
+            *packet_id = self.last_packet_id + 1;
+            true
+        } else {
+            false
+        };
+        let data = match packet.to_bytes() {
+            Ok(data) => data,
+            Err(e) => {
+                println!("\x1b[0;31m[ERROR]\x1b[0m Could not encode packet: {e}");
+                return false;
+            }
+        };
+        let sent = self.send_raw(&data);
+        if sent && numbered {
+            self.last_packet_id += 1;
+        }
+        sent
+    }
+
+    /// Sends the packet, or holds on to it until `flush` if bundles are used.
+    pub fn queue(&mut self, packet: PacketType) -> Queued {
+        if !(self.want_bundles && self.features.has(server_features::BUNDLES)) {
+            return if self.send(packet) {
+                Queued::Sent
+            } else {
+                Queued::Dropped
+            };
+        }
+        if self.backing_off() {
+            return Queued::Dropped;
+        }
+        let bundled = match BundledPacket::new(packet) {
+            Ok(bundled) => bundled,
+            Err(e) => {
+                println!("\x1b[0;31m[ERROR]\x1b[0m Could not encode packet: {e}");
+                return Queued::Dropped;
+            }
+        };
+        if self.bundle_size + bundled.size() > MAX_BUNDLE_SIZE {
+            self.send_bundle();
+        }
+        self.bundle_size += bundled.size();
+        self.bundle.push(bundled);
+        Queued::Bundled
+    }
+
+    /// Sends all queued packets as one bundle. Returns whether all packets bundled since the last
+    /// flush were sent.
+    pub fn flush(&mut self) -> bool {
+        self.send_bundle();
+        !take(&mut self.bundle_lost)
+    }
+
+    fn send_bundle(&mut self) {
+        if self.bundle.is_empty() {
+            return;
+        }
+        self.bundle_size = BUNDLE_HEADER_SIZE;
+        let packets = take(&mut self.bundle);
+        if !self.send(PacketType::Bundle {
+            packet_id: 0,
+            packets,
+        }) {
+            self.bundle_lost = true;
+        }
+    }
+
+    /// Only tells the server about battery changes.
+    pub fn send_battery(&mut self, battery: Battery) {
+        if self.sent_battery == Some(battery) {
+            return;
+        }
+        self.sent_battery = Some(battery);
+        self.send(PacketType::BatteryLevel {
+            packet_id: 0,
+            voltage: battery.voltage(),
+            level: battery.level(),
+        });
+    }
+
+    fn send_handshake(&mut self) {
+        let handshake = PacketType::Handshake {
+            packet_id: 0,
+            board: 0,
+            imu: 0,
+            mcu_type: 0,
+            imu_info: (0, 0, 0),
+            build: 9,
+            firmware: "slimevr-wrangler".to_string().into(),
+            mac_address: self.mac,
+        };
+        self.send(handshake);
+    }
+
+    /// Sends our feature flags until the server answers with its own, or gives up on old servers.
+    fn request_features(&mut self) {
+        if self.status != ServerStatus::Connected
+            || self.features != ServerFeatures::Unknown
+            || self.last_feature_request.elapsed() < FEATURE_REQUEST_INTERVAL
+        {
+            return;
+        }
+        if self.feature_requests >= FEATURE_REQUEST_ATTEMPTS {
+            println!("[INFO] SlimeVR Server does not support feature flags.");
+            self.features = ServerFeatures::Unsupported;
+            return;
+        }
+        self.feature_requests += 1;
+        self.last_feature_request = Instant::now();
+        self.send(PacketType::FeatureFlags {
+            packet_id: 0,
+            flags: WRANGLER_FEATURES.to_vec(),
+        });
+    }
+
+    /// Handshakes with the server until it answers, and handles everything the server sent.
+    pub fn update(&mut self, buf: &mut [u8]) -> Vec<Event> {
+        let mut events = vec![];
+        // After a network error the handshake is retried as soon as the backoff is over.
+        if self.status != ServerStatus::Connected
+            && !self.backing_off()
+            && (self.status == ServerStatus::NetworkError
+                || self.last_handshake.elapsed().as_secs() >= 3)
+        {
+            self.last_handshake = Instant::now();
+            self.send_handshake();
+            events.push(Event::HandshakeSent);
+        }
+        while let Some(Ok((len, from))) = self.socket.as_ref().map(|s| s.recv_from(buf)) {
+            let packet = ServerPacket::from_bytes((&buf[0..len], 0));
+            // All servers that answer are reported, but only the first one is used.
+            if self.discovery.is_some() && (self.searching || from != self.address) {
+                if !matches!(packet, Ok((_, ServerPacket::HandshakeResponse { .. }))) {
+                    continue;
+                }
+                events.push(Event::Discovered(from));
+                if !self.searching {
+                    continue;
+                }
+                println!("[INFO] Found SlimeVR Server at {from}.");
+                self.address = from;
+                self.searching = false;
+            }
+            if self.status == ServerStatus::Disconnected {
+                self.status = ServerStatus::Unknown;
+            }
+            match packet {
+                Ok((_, ServerPacket::Ping { .. })) => {
+                    self.last_ping = Instant::now();
+                    self.send_raw(&buf[0..len]);
+                }
+                Ok((_, ServerPacket::Heartbeat { .. })) => {
+                    self.send(PacketType::Heartbeat { packet_id: 0 });
+                }
+                Ok((_, packet @ ServerPacket::HandshakeResponse { .. })) => {
+                    self.status = ServerStatus::Connected;
+                    self.protocol_version = packet.protocol_version();
+                    self.features = ServerFeatures::Unknown;
+                    self.feature_requests = 0;
+                    self.sent_battery = None;
+                    events.push(Event::Connected);
+                }
+                Ok((_, ServerPacket::FeatureFlags { flags, .. })) => {
+                    println!(
+                        "[INFO] SlimeVR Server protocol version: {}, bundle support: {}.",
+                        self.protocol_version
+                            .map_or("unknown".to_string(), |v| v.to_string()),
+                        has_feature(&flags, server_features::BUNDLES),
+                    );
+                    self.features = ServerFeatures::Known(flags);
+                }
+                // Sensor info acks and sensor config changes are not used yet.
+                Ok((_, ServerPacket::SensorInfo { .. }))
+                | Ok((_, ServerPacket::SetConfigFlag { .. }))
+                | Err(_) => {}
+            }
+        }
+        self.request_features();
+        if matches!(self.status, ServerStatus::Unknown | ServerStatus::Connected)
+            && self.last_ping.elapsed().as_secs() >= 3
+        {
+            self.status = ServerStatus::Disconnected;
+            if let Some(target) = self.discovery {
+                self.address = target;
+                self.searching = true;
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn discovers_mock_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let server_address = server.local_addr().unwrap();
+
+        let local = [SocketAddr::from(([127, 0, 0, 1], 0))];
+        let mut connection = Connection::bind(&local, discovery_address(), [0; 6]);
+        connection.start_discovery(server_address);
+
+        let mut buf = [0; 512];
+        assert_eq!(connection.update(&mut buf), vec![Event::HandshakeSent]);
+
+        let (len, tracker) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[0..4], &[0, 0, 0, 3]);
+        assert!(len > 12);
+        server.send_to(b"\x03Hey OVR =D 5", tracker).unwrap();
+
+        let mut events = vec![];
+        for _ in 0..100 {
+            events.extend(connection.update(&mut buf));
+            if connection.status == ServerStatus::Connected {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            events,
+            vec![Event::Discovered(server_address), Event::Connected]
+        );
+        assert_eq!(connection.address, server_address);
+        assert_eq!(connection.protocol_version, Some(5));
+    }
+
+    #[test]
+    fn reports_lost_bundles() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = [SocketAddr::from(([127, 0, 0, 1], 0))];
+        let mut connection = Connection::bind(&local, server.local_addr().unwrap(), [0; 6]);
+        connection.want_bundles = true;
+        connection.features = ServerFeatures::Known(vec![1]);
+        let heartbeat = || PacketType::Heartbeat { packet_id: 0 };
+
+        assert_eq!(connection.queue(heartbeat()), Queued::Bundled);
+        assert!(connection.flush());
+
+        assert_eq!(connection.queue(heartbeat()), Queued::Bundled);
+        connection.status = ServerStatus::NetworkError;
+        connection.retry_at = Instant::now() + Duration::from_secs(60);
+        assert!(!connection.flush());
+        assert_eq!(connection.queue(heartbeat()), Queued::Dropped);
+        assert!(connection.flush());
+    }
+
+    #[test]
+    fn recovers_from_network_error() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = [SocketAddr::from(([127, 0, 0, 1], 0))];
+        let mut connection = Connection::bind(&local, server.local_addr().unwrap(), [0; 6]);
+        let mut buf = [0; 512];
+        connection.update(&mut buf);
+        let sent_before = connection.last_packet_id;
+
+        connection.status = ServerStatus::NetworkError;
+        connection.retry_at = Instant::now() + Duration::from_secs(60);
+        assert!(!connection.send(PacketType::Heartbeat { packet_id: 0 }));
+        assert_eq!(connection.last_packet_id, sent_before);
+        assert!(connection.update(&mut buf).is_empty());
+
+        // Once the backoff is over one handshake is sent, and the next waits for the timer.
+        connection.retry_at = Instant::now();
+        assert_eq!(connection.update(&mut buf), vec![Event::HandshakeSent]);
+        assert_eq!(connection.status, ServerStatus::Disconnected);
+        assert!(connection.update(&mut buf).is_empty());
+        assert!(connection.send(PacketType::Heartbeat { packet_id: 0 }));
+        assert_eq!(connection.last_packet_id, sent_before + 1);
+    }
+}
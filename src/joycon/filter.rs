@@ -0,0 +1,182 @@
+//! Orientation filters that turn gyro and accelerometer samples into a rotation.
+//!
+//! All filters use the same convention as VQF: the rotation maps from the sensor frame to an earth
+//! frame with Z pointing up, gyro is in radians/s and acceleration points up when at rest.
+
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use vqf_cxx::{VQFBuilder, VQF};
+
+use crate::settings::{FilterKind, FilterSettings};
+
+/// Time between samples from the controllers, when nothing is lost on the way.
+pub const SAMPLE_PERIOD: f64 = 0.005;
+
+pub trait Filter {
+    /// `dt` is the time in seconds since the previous sample.
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64);
+    fn rotation(&self) -> UnitQuaternion<f64>;
+}
+
+pub fn new(settings: &FilterSettings) -> Box<dyn Filter> {
+    match settings.kind {
+        FilterKind::Vqf => Box::new(Vqf::new(settings)),
+        FilterKind::Madgwick => Box::new(Madgwick::new(settings)),
+        FilterKind::Mahony => Box::new(Mahony::new(settings)),
+    }
+}
+
+pub struct Vqf {
+    vqf: VQF,
+    /// Time that has passed but not been stepped yet, in seconds.
+    pending: f64,
+}
+impl Vqf {
+    fn new(settings: &FilterSettings) -> Self {
+        Self {
+            vqf: VQFBuilder::new(SAMPLE_PERIOD)
+                .tau_acc(settings.tau_acc)
+                .rest_bias_est_enabled(settings.rest_detection)
+                .motion_bias_est_enabled(settings.bias_estimation)
+                .build(),
+            pending: 0.0,
+        }
+    }
+}
+impl Filter for Vqf {
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64) {
+        // VQF only supports a fixed sample period, so it gets as many steps as fit in the time
+        // that passed, with the rest carried over. Scaling the gyro instead would look like a
+        // spike in the turn rate to its rest detection and bias estimation.
+        for _ in 0..fixed_steps(&mut self.pending, dt) {
+            self.vqf.update_6dof(&gyro.data.0[0], &acc.data.0[0]);
+        }
+    }
+    fn rotation(&self) -> UnitQuaternion<f64> {
+        UnitQuaternion::new_unchecked(self.vqf.get_quat_6d().into())
+    }
+}
+
+/// Number of `SAMPLE_PERIOD` steps to take after `dt` seconds, keeping what is left in `pending`.
+fn fixed_steps(pending: &mut f64, dt: f64) -> usize {
+    *pending += dt;
+    let steps = (*pending / SAMPLE_PERIOD).round().max(0.0);
+    *pending -= steps * SAMPLE_PERIOD;
+    steps as usize
+}
+
+/// Integrates the gyro and steps along the gradient towards the accelerometer with a fixed rate.
+pub struct Madgwick {
+    beta: f64,
+    q: Quaternion<f64>,
+}
+impl Madgwick {
+    fn new(settings: &FilterSettings) -> Self {
+        Self {
+            beta: settings.beta,
+            q: Quaternion::identity(),
+        }
+    }
+}
+impl Filter for Madgwick {
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64) {
+        let mut q_dot = self.q * Quaternion::from_imag(gyro) * 0.5;
+        if let Some(a) = acc.try_normalize(1e-9) {
+            let (w, x, y, z) = (self.q.w, self.q.i, self.q.j, self.q.k);
+            // Difference between the estimated and measured gravity direction.
+            let f = Vector3::new(
+                2.0 * (x * z - w * y) - a.x,
+                2.0 * (w * x + y * z) - a.y,
+                2.0 * (0.5 - x * x - y * y) - a.z,
+            );
+            let step = Quaternion::new(
+                -2.0 * y * f.x + 2.0 * x * f.y,
+                2.0 * z * f.x + 2.0 * w * f.y - 4.0 * x * f.z,
+                -2.0 * w * f.x + 2.0 * z * f.y - 4.0 * y * f.z,
+                2.0 * x * f.x + 2.0 * y * f.y,
+            );
+            if let Some(step) = step.coords.try_normalize(1e-9) {
+                q_dot -= Quaternion::from(step) * self.beta;
+            }
+        }
+        self.q = (self.q + q_dot * dt).normalize();
+    }
+    fn rotation(&self) -> UnitQuaternion<f64> {
+        UnitQuaternion::new_unchecked(self.q)
+    }
+}
+
+/// Corrects the gyro towards the accelerometer with a proportional and an integral gain. The
+/// integral part estimates the gyro bias.
+pub struct Mahony {
+    kp: f64,
+    ki: f64,
+    integral: Vector3<f64>,
+    q: UnitQuaternion<f64>,
+}
+impl Mahony {
+    fn new(settings: &FilterSettings) -> Self {
+        Self {
+            kp: settings.kp,
+            ki: if settings.bias_estimation {
+                settings.ki
+            } else {
+                0.0
+            },
+            integral: Vector3::zeros(),
+            q: UnitQuaternion::identity(),
+        }
+    }
+}
+impl Filter for Mahony {
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64) {
+        let mut gyro = gyro;
+        if let Some(a) = acc.try_normalize(1e-9) {
+            let up = self.q.inverse_transform_vector(&Vector3::z());
+            let error = a.cross(&up);
+            self.integral += error * self.ki * dt;
+            gyro += error * self.kp + self.integral;
+        }
+        let q_dot = self.q.into_inner() * Quaternion::from_imag(gyro) * 0.5;
+        self.q = UnitQuaternion::from_quaternion(self.q.into_inner() + q_dot * dt);
+    }
+    fn rotation(&self) -> UnitQuaternion<f64> {
+        self.q
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_follow_time() {
+        let mut pending = 0.0;
+        let dts = [0.005, 0.1, 0.0, 0.0, 0.0075, 0.0025, 0.003, 0.006];
+        let steps: Vec<usize> = dts
+            .iter()
+            .map(|dt| fixed_steps(&mut pending, *dt))
+            .collect();
+        assert_eq!(steps, [1, 20, 0, 0, 2, 0, 1, 1]);
+        let stepped = steps.iter().sum::<usize>() as f64 * SAMPLE_PERIOD;
+        assert!((stepped + pending - dts.iter().sum::<f64>()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn converges_to_gravity() {
+        let tilt = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 1.0);
+        // What a still sensor with that rotation measures.
+        let acc = tilt.inverse_transform_vector(&Vector3::z());
+        for kind in [FilterKind::Madgwick, FilterKind::Mahony] {
+            let mut filter = new(&FilterSettings {
+                kind,
+                bias_estimation: false,
+                ..Default::default()
+            });
+            for _ in 0..4000 {
+                filter.update(Vector3::zeros(), acc, SAMPLE_PERIOD);
+            }
+            let up = filter.rotation() * acc;
+            assert!((up - Vector3::z()).norm() < 0.01, "{kind:?}: {up}");
+        }
+    }
+}
@@ -1,7 +1,15 @@
-use nalgebra::{Quaternion, UnitQuaternion, Vector3};
-use vqf_cxx::{VQFBuilder, VQF};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use nalgebra::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use super::filter::{self, Filter, SAMPLE_PERIOD};
+use crate::settings::FilterSettings;
+
+/// Longest gap between two samples that is integrated. Anything longer is most likely a reconnect.
+const MAX_DT: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct JoyconAxisData {
     pub accel_x: f64,
     pub accel_y: f64,
@@ -9,30 +17,154 @@ pub struct JoyconAxisData {
     pub gyro_x: f64,
     pub gyro_y: f64,
     pub gyro_z: f64,
+    /// When the sample was taken, in seconds since the unix epoch. 0 if unknown.
+    #[serde(default)]
+    pub time: f64,
+}
+
+/// Time in seconds between the previous and this sample, 0 if the time didn't move forward.
+/// Without timestamps, it assumes that no samples were lost.
+pub fn sample_dt(last_time: f64, time: f64) -> f64 {
+    if last_time > 0.0 && time > 0.0 {
+        (time - last_time).clamp(0.0, MAX_DT)
+    } else {
+        SAMPLE_PERIOD
+    }
+}
+
+/// Keeps the times of a device's samples increasing, when they are worked out from when the
+/// reports arrived. A report that arrives late, followed by one on time, would otherwise go back
+/// in time.
+#[derive(Default)]
+pub struct FrameClock {
+    last: f64,
+    /// Time left out of the stamps.
+    skipped: f64,
+}
+impl FrameClock {
+    /// Smallest step between two samples.
+    const MIN_STEP: f64 = 1e-6;
+
+    pub fn stamp(&mut self, time: f64) -> f64 {
+        self.last = (time - self.skipped).max(self.last + Self::MIN_STEP);
+        self.last
+    }
+
+    /// Leaves out `duration` seconds in which reports were dropped, so the samples after them
+    /// don't integrate over the gap.
+    pub fn skip(&mut self, duration: f64) {
+        self.skipped += duration;
+    }
+}
+
+/// Converts a time to the format of `JoyconAxisData::time`.
+pub fn timestamp(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// Integrates the gyro around one axis, for calibrating the gyro scale.
+pub struct AngleMeasure {
+    axis: usize,
+    /// Radians turned so far.
+    pub angle: f64,
+    last_time: f64,
+}
+impl AngleMeasure {
+    pub fn new(axis: usize) -> Self {
+        Self {
+            axis,
+            angle: 0.0,
+            last_time: 0.0,
+        }
+    }
+    pub fn update(&mut self, frame: &JoyconAxisData) {
+        let dt = sample_dt(self.last_time, frame.time);
+        self.last_time = frame.time;
+        self.angle += [frame.gyro_x, frame.gyro_y, frame.gyro_z][self.axis] * dt;
+    }
 }
 
 pub struct Imu {
-    vqf: VQF,
+    filter: Box<dyn Filter>,
+    /// What the filter was made from, to know when it has to be remade.
+    pub filter_settings: FilterSettings,
     pub rotation: UnitQuaternion<f64>,
+    last_time: f64,
 }
 impl Imu {
-    pub fn new() -> Self {
+    pub fn new(filter_settings: FilterSettings) -> Self {
         Self {
-            vqf: VQFBuilder::new(0.005f64).build(),
-            rotation: UnitQuaternion::new_unchecked(Quaternion::new(
-                1.0f64, 0.0f64, 0.0f64, 0.0f64,
-            )),
+            filter: filter::new(&filter_settings),
+            filter_settings,
+            rotation: UnitQuaternion::identity(),
+            last_time: 0.0,
         }
     }
     pub fn update(&mut self, frame: JoyconAxisData) {
         let gyro = Vector3::new(frame.gyro_x, frame.gyro_y, frame.gyro_z);
         let acc = Vector3::new(frame.accel_x, frame.accel_y, frame.accel_z);
-        self.vqf.update_6dof(&gyro.data.0[0], &acc.data.0[0]);
-        self.rotation = UnitQuaternion::new_unchecked(self.vqf.get_quat_6d().into());
+        let dt = sample_dt(self.last_time, frame.time);
+        self.last_time = frame.time;
+        self.filter.update(gyro, acc, dt);
+        self.rotation = self.filter.rotation();
     }
-    // euler_angles: roll, pitch, yaw
-    pub fn euler_angles_deg(&self) -> (f64, f64, f64) {
-        let ea = self.rotation.euler_angles();
-        (ea.0.to_degrees(), ea.1.to_degrees(), ea.2.to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::FilterKind;
+
+    #[test]
+    fn jitter_adds_no_time() {
+        // Reports of three samples 5ms apart, sent every 15ms but arriving with jitter.
+        let arrivals = [0.0, 0.025, 0.030, 0.045, 0.066, 0.075, 0.090, 0.112, 0.120];
+        let mut clock = FrameClock::default();
+        let times: Vec<f64> = arrivals
+            .iter()
+            .flat_map(|arrived| [0.010, 0.005, 0.0].map(|ago| 1000.0 + arrived - ago))
+            .map(|time| clock.stamp(time))
+            .collect();
+        let integrated: f64 = times.windows(2).map(|t| sample_dt(t[0], t[1])).sum();
+        assert!((integrated - (0.120 + 0.010)).abs() < 1e-4, "{integrated}");
+    }
+
+    #[test]
+    fn skipped_time_is_left_out() {
+        let mut clock = FrameClock::default();
+        let before = clock.stamp(1000.0);
+        clock.skip(0.2);
+        let after = clock.stamp(1000.205);
+        assert!((sample_dt(before, after) - 0.005).abs() < 1e-9);
+    }
+
+    #[test]
+    fn integrates_sample_times() {
+        let mut imu = Imu::new(FilterSettings {
+            kind: FilterKind::Madgwick,
+            ..Default::default()
+        });
+        let mut frame = JoyconAxisData {
+            accel_x: 0.0,
+            accel_y: 0.0,
+            accel_z: 1.0,
+            gyro_x: 0.0,
+            gyro_y: 0.0,
+            gyro_z: 1.0,
+            time: 1000.0,
+        };
+        // 15ms apart, as if every second report was dropped, and then a 50ms gap.
+        for _ in 0..20 {
+            imu.update(frame);
+            frame.time += 0.015;
+        }
+        frame.time += 0.05 - 0.015;
+        imu.update(frame);
+        let yaw = imu.rotation.euler_angles().2;
+        assert!(
+            (yaw - (SAMPLE_PERIOD + 19.0 * 0.015 + 0.05)).abs() < 1e-4,
+            "{yaw}"
+        );
     }
 }
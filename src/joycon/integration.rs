@@ -1,6 +1,8 @@
 use super::communication::ChannelData;
-use super::imu::JoyconAxisData;
-use super::{Battery, ChannelInfo, JoyconDesign, JoyconDesignType};
+use super::filter::SAMPLE_PERIOD;
+use super::imu::{timestamp, FrameClock, JoyconAxisData};
+use super::temperature::parse_temperature_reply;
+use super::{Battery, Button, ChannelInfo, JoyconDesign, JoyconDesignType};
 use crate::settings;
 use joycon_rs::joycon::device::calibration::imu::IMUCalibration;
 use joycon_rs::joycon::lights::{LightUp, Lights};
@@ -8,7 +10,7 @@ use joycon_rs::prelude::input_report_mode::BatteryLevel;
 use joycon_rs::prelude::*;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 // Gyro: 2000dps
 // Accel: 8G
@@ -29,6 +31,21 @@ fn gyro(n: i16, offset: i16, scale: f64) -> f64 {
     .to_radians() // radians/s
 }
 
+/// How often the IMU temperature is read. Reading it blocks the input reports for a moment and it
+/// only changes slowly.
+const TEMPERATURE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reads the IMU temperature in celsius from its OUT_TEMP registers.
+fn read_temperature<D: JoyConDriver>(driver: &mut D) -> Option<f64> {
+    let reply = driver
+        .send_sub_command(SubCommand::ReadIMURegisters, &[0x20, 2])
+        .ok()?;
+    let SubCommandReply::Checked(reply) = reply else {
+        return None;
+    };
+    parse_temperature_reply(&reply)
+}
+
 fn convert_battery(battery: BatteryLevel) -> Battery {
     match battery {
         BatteryLevel::Empty => Battery::Empty,
@@ -39,6 +56,29 @@ fn convert_battery(battery: BatteryLevel) -> Battery {
     }
 }
 
+const BUTTONS: [(Buttons, Button); 20] = [
+    (Buttons::A, Button::A),
+    (Buttons::B, Button::B),
+    (Buttons::X, Button::X),
+    (Buttons::Y, Button::Y),
+    (Buttons::Up, Button::Up),
+    (Buttons::Down, Button::Down),
+    (Buttons::Left, Button::Left),
+    (Buttons::Right, Button::Right),
+    (Buttons::L, Button::L),
+    (Buttons::R, Button::R),
+    (Buttons::ZL, Button::ZL),
+    (Buttons::ZR, Button::ZR),
+    (Buttons::SL, Button::SL),
+    (Buttons::SR, Button::SR),
+    (Buttons::Minus, Button::Minus),
+    (Buttons::Plus, Button::Plus),
+    (Buttons::LStick, Button::LStick),
+    (Buttons::RStick, Button::RStick),
+    (Buttons::Home, Button::Home),
+    (Buttons::Capture, Button::Capture),
+];
+
 fn convert_design(device_type: &JoyConDeviceType) -> JoyconDesignType {
     match device_type {
         JoyConDeviceType::JoyConL => JoyconDesignType::Left,
@@ -48,7 +88,7 @@ fn convert_design(device_type: &JoyConDeviceType) -> JoyconDesignType {
 }
 
 fn joycon_listen_loop(
-    standard: StandardFullMode<SimpleJoyConDriver>,
+    mut standard: StandardFullMode<SimpleJoyConDriver>,
     tx: &mpsc::Sender<ChannelData>,
     calib: IMUCalibration,
     settings: &settings::Handler,
@@ -68,6 +108,12 @@ fn joycon_listen_loop(
         JoyConDeviceType::JoyConL | JoyConDeviceType::ProCon => |v| v,
     };
     let mut last_battery = None;
+    let mut clock = FrameClock::default();
+    let mut last_pressed = [false; BUTTONS.len()];
+    let mut next_temperature = Instant::now();
+    // Stops asking for the temperature if the controller doesn't answer, without it the gyro
+    // drift is just not compensated.
+    let mut temperature_readable = true;
     loop {
         match standard.read_input_report() {
             Ok(report) => {
@@ -80,20 +126,32 @@ fn joycon_listen_loop(
                         ))
                         .unwrap();
                     }
-                    if report.common.pushed_buttons.contains(Buttons::Up)
-                        || report.common.pushed_buttons.contains(Buttons::B)
-                    {
-                        tx.send(ChannelData::new(serial_number.clone(), ChannelInfo::Reset))
+                    for (i, (joycon_button, button)) in BUTTONS.iter().enumerate() {
+                        let pressed = report.common.pushed_buttons.contains(*joycon_button);
+                        if pressed != last_pressed[i] {
+                            last_pressed[i] = pressed;
+                            tx.send(ChannelData::new(
+                                serial_number.clone(),
+                                ChannelInfo::Button(*button, pressed),
+                            ))
                             .unwrap();
+                        }
                     }
                     let gyro_scale_factor = settings.load().joycon_scale_get(&serial_number);
-                    let imu_data = report.extra.data.map(|data| JoyconAxisData {
-                        accel_x: acc(data.accel_x, calib.0[0]),
-                        accel_y: neg_right(acc(data.accel_y, calib.0[1])),
-                        accel_z: neg_right(acc(data.accel_z, calib.0[2])),
-                        gyro_x: gyro(data.gyro_1, calib.1[0], gyro_scale_factor),
-                        gyro_y: neg_right(gyro(data.gyro_2, calib.1[1], gyro_scale_factor)),
-                        gyro_z: neg_right(gyro(data.gyro_3, calib.1[2], gyro_scale_factor)),
+                    // The report has three samples 5ms apart, with the newest last.
+                    let received = timestamp(SystemTime::now());
+                    let frames = &report.extra.data;
+                    let imu_data = [0, 1, 2].map(|i| {
+                        let data = &frames[i];
+                        JoyconAxisData {
+                            accel_x: acc(data.accel_x, calib.0[0]),
+                            accel_y: neg_right(acc(data.accel_y, calib.0[1])),
+                            accel_z: neg_right(acc(data.accel_z, calib.0[2])),
+                            gyro_x: gyro(data.gyro_1, calib.1[0], gyro_scale_factor[0]),
+                            gyro_y: neg_right(gyro(data.gyro_2, calib.1[1], gyro_scale_factor[1])),
+                            gyro_z: neg_right(gyro(data.gyro_3, calib.1[2], gyro_scale_factor[2])),
+                            time: clock.stamp(received - (2 - i) as f64 * SAMPLE_PERIOD),
+                        }
                     });
                     tx.send(ChannelData::new(
                         serial_number.clone(),
@@ -101,6 +159,22 @@ fn joycon_listen_loop(
                     ))
                     .unwrap();
                 }
+                if temperature_readable && next_temperature <= Instant::now() {
+                    let started = Instant::now();
+                    next_temperature = started + TEMPERATURE_INTERVAL;
+                    let temperature = read_temperature(standard.driver_mut());
+                    // The reports that came while waiting for the reply were dropped.
+                    clock.skip(started.elapsed().as_secs_f64());
+                    match temperature {
+                        Some(temperature) => tx
+                            .send(ChannelData::new(
+                                serial_number.clone(),
+                                ChannelInfo::Temperature(temperature),
+                            ))
+                            .unwrap(),
+                        None => temperature_readable = false,
+                    }
+                }
             }
             Err(JoyConError::Disconnected) => {
                 tx.send(ChannelData::new(serial_number, ChannelInfo::Disconnected))
@@ -11,7 +11,8 @@ use upower_dbus::{DeviceProxy, UPowerProxy};
 use crate::settings;
 
 use super::{
-    imu::JoyconAxisData, Battery, ChannelData, ChannelInfo, JoyconDesign, JoyconDesignType,
+    imu::{timestamp, JoyconAxisData},
+    Battery, Button, ChannelData, ChannelInfo, JoyconDesign, JoyconDesignType,
 };
 
 // Resolution definitions from hid-nintendo.c from linux:
@@ -54,16 +55,48 @@ fn convert_battery(battery: upower_dbus::BatteryLevel) -> Battery {
     }
 }
 
+// Key codes from hid-nintendo.c. SL and SR use the shoulder button codes of the other side.
+fn convert_key(key: Key, design: JoyconDesignType) -> Option<Button> {
+    Some(match (key, design) {
+        (Key::BTN_TR, JoyconDesignType::Left) | (Key::BTN_TL, JoyconDesignType::Right) => {
+            Button::SL
+        }
+        (Key::BTN_TR2, JoyconDesignType::Left) | (Key::BTN_TL2, JoyconDesignType::Right) => {
+            Button::SR
+        }
+        (Key::BTN_TL, _) => Button::L,
+        (Key::BTN_TL2, _) => Button::ZL,
+        (Key::BTN_TR, _) => Button::R,
+        (Key::BTN_TR2, _) => Button::ZR,
+        (Key::BTN_EAST, _) => Button::A,
+        (Key::BTN_SOUTH, _) => Button::B,
+        (Key::BTN_NORTH, _) => Button::X,
+        (Key::BTN_WEST, _) => Button::Y,
+        (Key::BTN_DPAD_UP, _) => Button::Up,
+        (Key::BTN_DPAD_DOWN, _) => Button::Down,
+        (Key::BTN_DPAD_LEFT, _) => Button::Left,
+        (Key::BTN_DPAD_RIGHT, _) => Button::Right,
+        (Key::BTN_SELECT, _) => Button::Minus,
+        (Key::BTN_START, _) => Button::Plus,
+        (Key::BTN_THUMBL, _) => Button::LStick,
+        (Key::BTN_THUMBR, _) => Button::RStick,
+        (Key::BTN_MODE, _) => Button::Home,
+        (Key::BTN_Z, _) => Button::Capture,
+        _ => return None,
+    })
+}
+
 async fn joycon_listener(tx: mpsc::Sender<ChannelData>, mut input: EventStream) {
     let mac = input.device().unique_name().unwrap().to_string(); // Joycons always have unique name
+    let design = convert_design(input.device().input_id().product());
 
     while let Ok(ev) = input.next_event().await {
         if let InputEventKind::Key(key) = ev.kind() {
-            // if DPAD_UP or BTN_SOUTH and button is lifted
-            if (key == Key::BTN_DPAD_UP || key == Key::BTN_SOUTH) && ev.value() == 0 {
+            // 0 is released, 1 is pressed and 2 is held down
+            if let (Some(button), 0 | 1) = (convert_key(key, design), ev.value()) {
                 tx.send(ChannelData {
                     serial_number: mac.clone(),
-                    info: ChannelInfo::Reset,
+                    info: ChannelInfo::Button(button, ev.value() == 1),
                 })
                 .unwrap();
             }
@@ -77,6 +110,8 @@ async fn joycon_listener(tx: mpsc::Sender<ChannelData>, mut input: EventStream)
     .unwrap();
 }
 
+// hid-nintendo doesn't expose the IMU temperature, so the gyro drift from warming up is not
+// compensated on this backend.
 async fn imu_listener(
     tx: mpsc::Sender<ChannelData>,
     settings: settings::Handler,
@@ -90,6 +125,7 @@ async fn imu_listener(
         gyro_x: 0.0,
         gyro_y: 0.0,
         gyro_z: 0.0,
+        time: 0.0,
     }; 3];
     let mut count = 0;
     let mut sys_time = SystemTime::now();
@@ -101,6 +137,8 @@ async fn imu_listener(
             last_event = input.device().get_abs_state().unwrap();
             continue;
         }
+        // The axis state is from the previous timestamp.
+        let time = timestamp(sys_time);
         sys_time = ev.timestamp();
 
         let gyro_scale_factor = settings.load().joycon_scale_get(&mac);
@@ -114,9 +152,10 @@ async fn imu_listener(
             accel_x: acc(accel_axis[0].value),
             accel_y: acc(accel_axis[1].value),
             accel_z: acc(accel_axis[2].value),
-            gyro_x: gyro(gyro_axis[0].value, gyro_scale_factor),
-            gyro_y: gyro(gyro_axis[1].value, gyro_scale_factor),
-            gyro_z: gyro(gyro_axis[2].value, gyro_scale_factor),
+            gyro_x: gyro(gyro_axis[0].value, gyro_scale_factor[0]),
+            gyro_y: gyro(gyro_axis[1].value, gyro_scale_factor[1]),
+            gyro_z: gyro(gyro_axis[2].value, gyro_scale_factor[2]),
+            time,
         };
 
         count += 1;
@@ -0,0 +1,150 @@
+//! Button combos from the settings that trigger actions, once they are held long enough.
+
+use std::time::{Duration, Instant};
+
+use super::Button;
+use crate::settings::ButtonMapping;
+
+/// Reads a combo like "l+up", with the buttons that are held together separated by plus signs.
+pub fn parse_combo(text: &str) -> Result<Vec<Button>, String> {
+    if text.trim().is_empty() {
+        return Err("A mapping has no buttons.".into());
+    }
+    text.split('+')
+        .map(|name| {
+            let name = name.trim().to_lowercase();
+            Button::ALL
+                .into_iter()
+                .find(|b| b.name() == name)
+                .ok_or_else(|| format!("\"{name}\" is not a button."))
+        })
+        .collect()
+}
+
+/// The buttons held down on one device, and the mappings that fired while they are held.
+#[derive(Default)]
+pub struct ButtonMapper {
+    held: Vec<(Button, Instant)>,
+    fired: Vec<usize>,
+}
+impl ButtonMapper {
+    pub fn button(&mut self, button: Button, pressed: bool, now: Instant) {
+        self.held.retain(|(b, _)| *b != button);
+        if pressed {
+            self.held.push((button, now));
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.held.clear();
+    }
+
+    /// Returns the indices of the mappings that fire at `now`. When several fire at once, the
+    /// ones whose buttons are all part of a bigger combo are left out, so that "l+up" doesn't
+    /// also trigger "up".
+    pub fn update(&mut self, mappings: &[ButtonMapping], now: Instant) -> Vec<usize> {
+        let held = &self.held;
+        let combos: Vec<Vec<Button>> = mappings
+            .iter()
+            .map(|m| parse_combo(&m.buttons).unwrap_or_default())
+            .collect();
+        // When the last button of a combo was pressed, if they are all held.
+        let held_since = |combo: &[Button]| -> Option<Instant> {
+            combo
+                .iter()
+                .map(|b| held.iter().find(|(h, _)| h == b).map(|(_, at)| *at))
+                .try_fold(None, |last: Option<Instant>, at| Some(last.max(Some(at?))))?
+        };
+
+        self.fired.retain(|i| held_since(&combos[*i]).is_some());
+        let due: Vec<usize> = mappings
+            .iter()
+            .enumerate()
+            .filter(|(i, mapping)| {
+                !self.fired.contains(i)
+                    && held_since(&combos[*i]).is_some_and(|since| {
+                        now.saturating_duration_since(since)
+                            >= Duration::from_secs_f64(mapping.hold.max(0.0))
+                    })
+            })
+            .map(|(i, _)| i)
+            .collect();
+        self.fired.extend(&due);
+
+        let covered = |i: usize, j: usize| {
+            combos[j].len() > combos[i].len() && combos[i].iter().all(|b| combos[j].contains(b))
+        };
+        due.iter()
+            .copied()
+            .filter(|i| !due.iter().any(|j| covered(*i, *j)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ButtonAction;
+
+    fn mapping(buttons: &str, hold: f64) -> ButtonMapping {
+        ButtonMapping {
+            buttons: buttons.into(),
+            hold,
+            action: ButtonAction::QuickReset,
+            osc_address: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_combos() {
+        assert_eq!(parse_combo(" L+Up "), Ok(vec![Button::L, Button::Up]));
+        assert!(parse_combo("l+jump").is_err());
+        assert!(parse_combo("").is_err());
+    }
+
+    #[test]
+    fn fires_combos_once() {
+        let mappings = [mapping("up", 0.0), mapping("l+up", 0.0), mapping("b", 0.0)];
+        let start = Instant::now();
+        let mut mapper = ButtonMapper::default();
+
+        // Holding L first makes it a full combo, without the single button.
+        mapper.button(Button::L, true, start);
+        assert!(mapper.update(&mappings, start).is_empty());
+        mapper.button(Button::Up, true, start);
+        assert_eq!(mapper.update(&mappings, start), vec![1]);
+        assert!(mapper.update(&mappings, start).is_empty());
+
+        // Pressing up again fires it again.
+        mapper.button(Button::Up, false, start);
+        assert!(mapper.update(&mappings, start).is_empty());
+        mapper.button(Button::L, false, start);
+        mapper.button(Button::Up, true, start);
+        assert_eq!(mapper.update(&mappings, start), vec![0]);
+    }
+
+    #[test]
+    fn waits_for_hold() {
+        let mappings = [mapping("up", 0.0), mapping("up", 2.0)];
+        let start = Instant::now();
+        let mut mapper = ButtonMapper::default();
+        mapper.button(Button::Up, true, start);
+        assert_eq!(mapper.update(&mappings, start), vec![0]);
+        assert!(mapper
+            .update(&mappings, start + Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(
+            mapper.update(&mappings, start + Duration::from_secs(2)),
+            vec![1]
+        );
+
+        // Letting go before the time is up doesn't fire it.
+        mapper.button(Button::Up, false, start);
+        mapper.button(Button::Up, true, start + Duration::from_secs(3));
+        mapper.update(&mappings, start + Duration::from_secs(3));
+        mapper.button(Button::Up, false, start + Duration::from_secs(4));
+        assert!(mapper
+            .update(&mappings, start + Duration::from_secs(6))
+            .is_empty());
+    }
+}
@@ -1,15 +1,28 @@
 //mod ui;
+mod bias;
+mod calibration;
+pub use calibration::{SixFace, FACES};
+mod filter;
 mod imu;
+mod mapping;
+pub use mapping::parse_combo;
+mod tap;
+mod temperature;
 
 mod communication;
 pub use communication::*;
+mod connection;
+mod osc;
 
 mod integration;
 #[cfg(target_os = "linux")]
 mod linux_integration;
 use integration::spawn_thread;
+mod replay_integration;
 mod test_integration;
 
+mod recorder;
+
 mod wrapper;
 pub use wrapper::*;
 
@@ -0,0 +1,272 @@
+//! Sends tracker data as OSC 1.0 messages over UDP, for VRChat and other OSC programs.
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use nalgebra::UnitQuaternion;
+
+use super::Button;
+use crate::settings;
+
+pub enum OscArg<'a> {
+    Int(i32),
+    Float(f32),
+    Str(&'a str),
+    Bool(bool),
+}
+
+/// OSC strings end with a null byte and are padded to a multiple of 4 bytes.
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+pub fn message(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut buf = vec![];
+    push_str(&mut buf, address);
+    let tags: String = std::iter::once(',')
+        .chain(args.iter().map(|arg| match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::Str(_) => 's',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+        }))
+        .collect();
+    push_str(&mut buf, &tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(i) => buf.extend_from_slice(&i.to_be_bytes()),
+            OscArg::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+            OscArg::Str(s) => push_str(&mut buf, s),
+            OscArg::Bool(_) => {}
+        }
+    }
+    buf
+}
+
+/// Fills in `{serial}` and `{button}` in an address template from the settings.
+fn address(template: &str, serial_number: &str, button: Option<Button>) -> String {
+    template
+        .replace("{serial}", serial_number)
+        .replace("{button}", button.map_or("", Button::name))
+}
+
+/// VMC receivers only need the status once per frame.
+pub const VMC_STATUS_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Rotation (x, y, z, w) in the Y-up, left-handed axes of Unity that VMC uses. The trackers are
+/// Z-up and right-handed, with Y forward. Swapping Y and Z flips the handedness, which turns the
+/// rotations the other way.
+fn unity_rotation(rotation: UnitQuaternion<f64>) -> [f32; 4] {
+    let q = rotation.coords;
+    [-q.x, -q.z, -q.y, q.w].map(|v| v as f32)
+}
+
+#[derive(Default)]
+pub struct OscSender {
+    socket: Option<UdpSocket>,
+    error_shown: bool,
+}
+impl OscSender {
+    fn send(&mut self, target: &str, data: &[u8]) {
+        let result = target
+            .parse::<SocketAddr>()
+            .map_err(|e| e.to_string())
+            .and_then(|target| {
+                if self.socket.is_none() {
+                    self.socket = Some(UdpSocket::bind(("0.0.0.0", 0)).map_err(|e| e.to_string())?);
+                }
+                let socket = self.socket.as_ref().unwrap();
+                socket.send_to(data, target).map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(_) => self.error_shown = false,
+            Err(e) => {
+                if !self.error_shown {
+                    println!("\x1b[0;31m[ERROR]\x1b[0m Could not send OSC to {target}: {e}");
+                    self.error_shown = true;
+                }
+            }
+        }
+    }
+
+    /// Sends the rotation as a quaternion (x, y, z, w) and as euler angles in degrees.
+    pub fn send_rotation(
+        &mut self,
+        osc: &settings::Osc,
+        serial_number: &str,
+        rotation: UnitQuaternion<f64>,
+    ) {
+        if !osc.rotation_address.is_empty() {
+            let q = rotation.coords;
+            let data = message(
+                &address(&osc.rotation_address, serial_number, None),
+                &[q.x, q.y, q.z, q.w].map(|v| OscArg::Float(v as f32)),
+            );
+            self.send(&osc.target, &data);
+        }
+        if !osc.euler_address.is_empty() {
+            let (roll, pitch, yaw) = rotation.euler_angles();
+            let data = message(
+                &address(&osc.euler_address, serial_number, None),
+                &[roll, pitch, yaw].map(|v| OscArg::Float(v.to_degrees() as f32)),
+            );
+            self.send(&osc.target, &data);
+        }
+    }
+
+    pub fn send_button(
+        &mut self,
+        osc: &settings::Osc,
+        serial_number: &str,
+        button: Button,
+        pressed: bool,
+    ) {
+        if !osc.button_address.is_empty() {
+            let data = message(
+                &address(&osc.button_address, serial_number, Some(button)),
+                &[OscArg::Bool(pressed)],
+            );
+            self.send(&osc.target, &data);
+        }
+    }
+
+    /// A message from a button mapping, to the address from the mapping.
+    pub fn send_event(&mut self, osc: &settings::Osc, template: &str, serial_number: &str) {
+        let data = message(
+            &address(template, serial_number, None),
+            &[OscArg::Bool(true)],
+        );
+        self.send(&osc.target, &data);
+    }
+
+    /// VMC tracker pose. Joy-Cons have no position, so it is always at the origin.
+    pub fn send_vmc_tracker(
+        &mut self,
+        vmc: &settings::Vmc,
+        name: &str,
+        rotation: UnitQuaternion<f64>,
+    ) {
+        let [x, y, z, w] = unity_rotation(rotation);
+        let data = message(
+            "/VMC/Ext/Tra/Pos",
+            &[
+                OscArg::Str(name),
+                OscArg::Float(0.0),
+                OscArg::Float(0.0),
+                OscArg::Float(0.0),
+                OscArg::Float(x),
+                OscArg::Float(y),
+                OscArg::Float(z),
+                OscArg::Float(w),
+            ],
+        );
+        self.send(&vmc.target, &data);
+    }
+
+    /// Tells VMC receivers that the performer is running, and its time in seconds.
+    pub fn send_vmc_status(&mut self, vmc: &settings::Vmc, time: f32) {
+        let ok = message("/VMC/Ext/OK", &[OscArg::Int(1)]);
+        self.send(&vmc.target, &ok);
+        let time = message("/VMC/Ext/T", &[OscArg::Float(time)]);
+        self.send(&vmc.target, &time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::{FRAC_1_SQRT_2, FRAC_PI_2};
+
+    use nalgebra::{Quaternion, Vector3};
+
+    use super::*;
+
+    #[test]
+    fn encodes_message() {
+        assert_eq!(
+            message("/a/b", &[OscArg::Float(1.0), OscArg::Bool(true)]),
+            b"/a/b\0\0\0\0,fT\0\x3f\x80\0\0"
+        );
+    }
+
+    #[test]
+    fn sends_to_listener() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let osc = settings::Osc {
+            enabled: true,
+            target: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let mut sender = OscSender::default();
+        let mut buf = [0; 512];
+
+        sender.send_rotation(&osc, "JC1", UnitQuaternion::identity());
+        let len = listener.recv(&mut buf).unwrap();
+        let mut expected = b"/wrangler/JC1/rotation\0\0,ffff\0\0\0".to_vec();
+        for v in [0.0f32, 0.0, 0.0, 1.0] {
+            expected.extend_from_slice(&v.to_be_bytes());
+        }
+        assert_eq!(&buf[..len], &expected[..]);
+
+        let len = listener.recv(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"/wrangler/JC1/euler\0,fff\0"));
+        assert_eq!(len, 20 + 8 + 12);
+
+        sender.send_button(&osc, "JC1", Button::ZL, false);
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"/wrangler/JC1/button/zl\0,F\0\0");
+    }
+
+    #[test]
+    fn converts_to_unity_axes() {
+        let swap = |v: Vector3<f64>| Vector3::new(v.x, v.z, v.y);
+        let unity = |rotation| {
+            let [x, y, z, w] = unity_rotation(rotation).map(f64::from);
+            UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z))
+        };
+        // Turning left from forward, around up.
+        let left = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), FRAC_PI_2);
+        let turned = unity(left) * Vector3::new(0.0, 0.0, 1.0);
+        assert!(
+            (turned - Vector3::new(-1.0, 0.0, 0.0)).norm() < 1e-6,
+            "{turned}"
+        );
+
+        let rotation = UnitQuaternion::from_euler_angles(0.3, -1.1, 2.0);
+        let v = Vector3::new(0.2, -0.5, 0.8);
+        assert!((unity(rotation) * swap(v) - swap(rotation * v)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn encodes_vmc_tracker() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let vmc = settings::Vmc {
+            enabled: true,
+            target: listener.local_addr().unwrap().to_string(),
+        };
+        let mut sender = OscSender::default();
+        let mut buf = [0; 512];
+
+        let left = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), FRAC_PI_2);
+        sender.send_vmc_tracker(&vmc, "hip", left);
+        let len = listener.recv(&mut buf).unwrap();
+        let mut expected = b"/VMC/Ext/Tra/Pos\0\0\0\0,sfffffff\0\0\0hip\0".to_vec();
+        let half = FRAC_1_SQRT_2 as f32;
+        for v in [0.0f32, 0.0, 0.0, -0.0, -half, -0.0, half] {
+            expected.extend_from_slice(&v.to_be_bytes());
+        }
+        assert_eq!(&buf[..len], &expected[..]);
+    }
+}
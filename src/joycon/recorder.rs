@@ -0,0 +1,126 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufWriter, Write},
+    path::PathBuf,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::communication::{ChannelData, ChannelInfo};
+
+/// One line in a recording file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recorded {
+    /// Seconds since the recording started.
+    pub time: f64,
+    pub data: ChannelData,
+}
+
+pub fn write_record(writer: &mut impl Write, record: &Recorded) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+/// Reads a recording, stopping at the first line that can't be read.
+pub fn read_records(reader: impl BufRead) -> impl Iterator<Item = Recorded> {
+    reader.lines().map_while(|line| {
+        let line = line.ok()?;
+        match serde_json::from_str(&line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                println!("\x1b[0;31m[ERROR]\x1b[0m Could not read recorded line: {e}");
+                None
+            }
+        }
+    })
+}
+
+/// Passes everything from `rx` on to `tx`, and writes a copy of it to the file at `path`.
+pub fn tee(rx: mpsc::Receiver<ChannelData>, tx: mpsc::Sender<ChannelData>, path: PathBuf) {
+    let mut writer = match File::create(&path) {
+        Ok(file) => Some(BufWriter::new(file)),
+        Err(e) => {
+            println!(
+                "\x1b[0;31m[ERROR]\x1b[0m Could not create recording {}: {e}",
+                path.display()
+            );
+            None
+        }
+    };
+    if writer.is_some() {
+        println!("[INFO] Recording controller data to {}", path.display());
+    }
+
+    let start = Instant::now();
+    let mut last_flush = Instant::now();
+    for data in rx {
+        // Exiting is not part of the recording, replaying it would stop the replay.
+        if matches!(data.info, ChannelInfo::Shutdown) {
+            if let Some(w) = writer.as_mut() {
+                w.flush().ok();
+            }
+        } else if let Some(w) = writer.as_mut() {
+            let record = Recorded {
+                time: start.elapsed().as_secs_f64(),
+                data: data.clone(),
+            };
+            let mut result = write_record(w, &record);
+            if result.is_ok() && last_flush.elapsed() > Duration::from_secs(1) {
+                last_flush = Instant::now();
+                result = w.flush();
+            }
+            if let Err(e) = result {
+                println!("\x1b[0;31m[ERROR]\x1b[0m Stopped recording: {e}");
+                writer = None;
+            }
+        }
+        if tx.send(data).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joycon::{imu::JoyconAxisData, Battery, ChannelInfo};
+
+    #[test]
+    fn records_round_trip() {
+        let frame = JoyconAxisData {
+            accel_x: 0.0,
+            accel_y: -1.0,
+            accel_z: 0.0,
+            gyro_x: 0.25,
+            gyro_y: 0.0,
+            gyro_z: -0.5,
+            time: 1.5,
+        };
+        let records = [
+            Recorded {
+                time: 0.0,
+                data: ChannelData::new("a".into(), ChannelInfo::ImuData([frame; 3])),
+            },
+            Recorded {
+                time: 0.015,
+                data: ChannelData::new("a".into(), ChannelInfo::Battery(Battery::Low)),
+            },
+        ];
+        let mut file = vec![];
+        for record in &records {
+            write_record(&mut file, record).unwrap();
+        }
+        file.extend_from_slice(b"not json\n");
+
+        let read: Vec<_> = read_records(&file[..]).collect();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].time, 0.015);
+        assert!(matches!(read[0].data.info, ChannelInfo::ImuData(f) if f == [frame; 3]));
+        assert!(matches!(
+            read[1].data.info,
+            ChannelInfo::Battery(Battery::Low)
+        ));
+    }
+}
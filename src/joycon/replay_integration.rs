@@ -0,0 +1,40 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{communication::ChannelData, recorder::read_records};
+
+/// Sends a recording made with `recorder::tee` again, `speed` times as fast as it was recorded.
+/// A speed of 0 sends everything without waiting.
+pub fn replay(tx: mpsc::Sender<ChannelData>, path: PathBuf, speed: f64) {
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!(
+                "\x1b[0;31m[ERROR]\x1b[0m Could not open recording {}: {e}",
+                path.display()
+            );
+            return;
+        }
+    };
+    println!("[INFO] Replaying {} at {speed}x speed", path.display());
+
+    let start = Instant::now();
+    for record in read_records(BufReader::new(file)) {
+        if speed > 0.0 {
+            let at = Duration::from_secs_f64(record.time.max(0.0) / speed);
+            if let Some(wait) = at.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        if tx.send(record.data).is_err() {
+            return;
+        }
+    }
+    println!("[INFO] Replay of {} finished", path.display());
+}
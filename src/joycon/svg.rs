@@ -1,4 +1,6 @@
 use iced::widget::svg::Handle;
+use nalgebra::{UnitQuaternion, Vector2};
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
     collections::{
@@ -11,36 +13,60 @@ static LEFT: &str = include_str!("../../assets/joycon-left.svg");
 static RIGHT: &str = include_str!("../../assets/joycon-right.svg");
 static PRO: &str = include_str!("../../assets/pro-controller.svg");
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum JoyconDesignType {
     Left,
     Right,
     Pro,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct JoyconDesign {
     pub color: String,
     pub design_type: JoyconDesignType,
 }
 
-fn generate(design: &JoyconDesign, rotation: i32) -> Handle {
+/// Shortest a side of the picture gets, so a Joy-Con seen from its side doesn't disappear.
+const MIN_SIDE: f64 = 0.3;
+
+/// How the face of the Joy-Con looks with the mounting, as the 2x2 matrix (a, b, c, d) of an svg
+/// `matrix` transform in hundredths. Turning it shows as turning the picture, flipping it mirrors
+/// the picture and laying it on its side squashes it.
+fn face_matrix(mounting: &UnitQuaternion<f64>) -> [i32; 4] {
+    let m = mounting.to_rotation_matrix();
+    let mut x = Vector2::new(m[(0, 0)], m[(1, 0)]);
+    let mut y = Vector2::new(m[(0, 1)], m[(1, 1)]);
+    // At most one side is seen edge on, and it keeps the side of the other it was on.
+    if y.norm() < MIN_SIDE {
+        let sign = if x.perp(&y) < 0.0 { -1.0 } else { 1.0 };
+        y = Vector2::new(-x.y, x.x).normalize() * MIN_SIDE * sign;
+    } else if x.norm() < MIN_SIDE {
+        let sign = if x.perp(&y) < 0.0 { -1.0 } else { 1.0 };
+        x = Vector2::new(y.y, -y.x).normalize() * MIN_SIDE * sign;
+    }
+    [x.x, x.y, y.x, y.y].map(|v| (v * 100.0).round() as i32)
+}
+
+fn generate(design: &JoyconDesign, matrix: [i32; 4]) -> Handle {
+    let [a, b, c, d] = matrix.map(|v| v as f64 / 100.0);
+    // Left starts with rail down. Right starts with rail up.
+    // The svg's are not consistent with that so needs to be rotated an extra 90 degrees.
     let svg_code = match design.design_type {
         JoyconDesignType::Left => LEFT,
         JoyconDesignType::Right => RIGHT,
         JoyconDesignType::Pro => PRO,
     }
     .replace("#3fa9f5", &design.color)
-    .replace("rotate(0", &format!("rotate({:}", (rotation + 90) % 360));
-    // Rotation is how many degrees clockwise joycons are rotated from their "starting position".
-    // Left starts with rail down. Right starts with rail up.
-    // The svg's are not consistent with that so needs to be rotated an extra 90 degrees.
+    .replace(
+        "rotate(0 128 128)",
+        &format!("translate(128 128) matrix({a} {b} {c} {d} 0 0) rotate(90) translate(-128 -128)"),
+    );
     Handle::from_memory(svg_code.as_bytes().to_vec())
 }
 
 #[derive(Clone, Debug)]
 pub struct Svg {
-    map: RefCell<HashMap<(JoyconDesign, i32), Handle>>,
+    map: RefCell<HashMap<(JoyconDesign, [i32; 4]), Handle>>,
 }
 impl Svg {
     pub fn new() -> Self {
@@ -48,10 +74,35 @@ impl Svg {
             map: RefCell::new(HashMap::new()),
         }
     }
-    pub fn get(&self, design: &JoyconDesign, rotation: i32) -> Handle {
-        match self.map.borrow_mut().entry((design.clone(), rotation)) {
+    /// Picture of the Joy-Con with its mounting.
+    pub fn get(&self, design: &JoyconDesign, mounting: &UnitQuaternion<f64>) -> Handle {
+        let matrix = face_matrix(mounting);
+        match self.map.borrow_mut().entry((design.clone(), matrix)) {
             Occupied(entry) => entry.get().clone(),
-            Vacant(entry) => entry.insert(generate(design, rotation)).clone(),
+            Vacant(entry) => entry.insert(generate(design, matrix)).clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::*;
+
+    #[test]
+    fn pictures_follow_mounting() {
+        let x = |degrees: f64| {
+            UnitQuaternion::from_axis_angle(&Vector3::x_axis(), degrees.to_radians())
+        };
+        let z = |degrees: f64| {
+            UnitQuaternion::from_axis_angle(&Vector3::z_axis(), degrees.to_radians())
+        };
+        assert_eq!(face_matrix(&UnitQuaternion::identity()), [100, 0, 0, 100]);
+        assert_eq!(face_matrix(&z(90.0)), [0, 100, -100, 0]);
+        // Buttons in is mirrored, and the sides are squashed.
+        assert_eq!(face_matrix(&x(180.0)), [100, 0, 0, -100]);
+        assert_eq!(face_matrix(&x(90.0)), [100, 0, 0, 30]);
+        assert_eq!(face_matrix(&(x(-90.0) * z(90.0))), [0, 30, -100, 0]);
+    }
+}
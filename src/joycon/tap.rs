@@ -0,0 +1,113 @@
+//! Finds taps on a controller in the accelerometer samples, for triggering actions when its
+//! buttons can't be reached.
+
+use nalgebra::Vector3;
+
+use super::imu::{sample_dt, JoyconAxisData};
+use crate::settings::Taps;
+
+/// Time constant of the average that a tap jolts away from, in seconds.
+const AVERAGE_TIME: f64 = 0.1;
+/// Time after a tap in which it is still shaking, and no new tap is counted.
+const SETTLE_TIME: f64 = 0.1;
+
+#[derive(Default)]
+pub struct TapDetector {
+    /// Recent average acceleration, none before the first sample.
+    average: Option<Vector3<f64>>,
+    last_time: f64,
+    /// Seconds since the detector started.
+    time: f64,
+    last_tap: f64,
+    /// Taps in the pattern so far.
+    taps: u32,
+}
+impl TapDetector {
+    /// Returns the number of taps when a pattern has ended, that is when no tap has followed
+    /// for `max_gap` seconds.
+    pub fn update(&mut self, frame: &JoyconAxisData, settings: &Taps) -> Option<u32> {
+        let dt = sample_dt(self.last_time, frame.time);
+        self.last_time = frame.time;
+        self.time += dt;
+        let acc = Vector3::new(frame.accel_x, frame.accel_y, frame.accel_z);
+        let average = self.average.get_or_insert(acc);
+        let jolt = (acc - *average).norm();
+        *average += (acc - *average) * (dt / AVERAGE_TIME).min(1.0);
+
+        let since_tap = self.time - self.last_tap;
+        let ended =
+            (self.taps > 0 && since_tap > settings.max_gap).then(|| std::mem::take(&mut self.taps));
+        if jolt > settings.threshold && (self.taps == 0 || since_tap > SETTLE_TIME) {
+            self.taps += 1;
+            self.last_tap = self.time;
+        }
+        ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: f64 = 0.005;
+
+    /// Lying still, with a short jolt at each of `taps` seconds.
+    fn frames(taps: &[f64], seconds: f64) -> Vec<JoyconAxisData> {
+        (0..(seconds / PERIOD) as usize)
+            .map(|i| {
+                let time = i as f64 * PERIOD;
+                let jolt = taps.iter().any(|t| (*t..*t + 0.015).contains(&time));
+                JoyconAxisData {
+                    accel_x: if jolt { 2.5 } else { 0.0 },
+                    accel_y: if jolt { -1.0 } else { 0.0 },
+                    accel_z: 1.0 + (i % 3) as f64 * 0.01,
+                    gyro_x: 0.0,
+                    gyro_y: 0.0,
+                    gyro_z: 0.0,
+                    time: 100.0 + time,
+                }
+            })
+            .collect()
+    }
+
+    fn patterns(frames: &[JoyconAxisData]) -> Vec<u32> {
+        let settings = Taps::default();
+        let mut detector = TapDetector::default();
+        frames
+            .iter()
+            .filter_map(|frame| detector.update(frame, &settings))
+            .collect()
+    }
+
+    #[test]
+    fn counts_taps() {
+        assert_eq!(patterns(&frames(&[1.0, 1.25], 3.0)), vec![2]);
+        assert_eq!(patterns(&frames(&[1.0, 1.2, 1.45], 3.0)), vec![3]);
+        // Too far apart to be one pattern.
+        assert_eq!(patterns(&frames(&[1.0, 2.0], 3.0)), vec![1, 1]);
+        // Not ended yet.
+        assert!(patterns(&frames(&[1.0, 1.25], 1.4)).is_empty());
+    }
+
+    #[test]
+    fn ignores_movement() {
+        // Tilting over in half a second, and swinging back and forth.
+        let frames: Vec<_> = (0..600)
+            .map(|i| {
+                let time = i as f64 * PERIOD;
+                let angle = (time * 2.0).min(1.0) * std::f64::consts::FRAC_PI_2;
+                let swing = (time * 6.0).sin() * 0.3;
+                JoyconAxisData {
+                    accel_x: angle.sin() + swing,
+                    accel_y: 0.0,
+                    accel_z: angle.cos(),
+                    gyro_x: 0.0,
+                    gyro_y: 0.0,
+                    gyro_z: 0.0,
+                    time: 100.0 + time,
+                }
+            })
+            .collect();
+        assert!(patterns(&frames).is_empty());
+    }
+}
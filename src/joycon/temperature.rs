@@ -0,0 +1,249 @@
+//! The gyro bias shifts as a Joy-Con warms up. The bias found while lying still is recorded at
+//! each temperature, and a line fitted through it removes the shift while moving.
+
+use nalgebra::Vector3;
+
+use super::imu::JoyconAxisData;
+use crate::settings::{DriftPoint, TemperatureDrift};
+
+/// Seconds between recording the bias while it keeps lying still.
+const RECORD_TIME: f64 = 30.0;
+/// Seconds of recording between saves, so the config isn't rewritten after every point.
+const SAVE_TIME: f64 = 300.0;
+/// Degrees celsius the points have to span before a line is fitted.
+const MIN_SPAN: f64 = 3.0;
+/// Most measurements a point averages, so it can still follow a slow change.
+const MAX_WEIGHT: f64 = 20.0;
+/// A larger slope is not drift, in radians/s per degree celsius.
+const MAX_SLOPE: f64 = 0.005;
+
+pub struct TemperatureCompensation {
+    drift: TemperatureDrift,
+    /// Latest IMU temperature in celsius, none when the backend can't read it.
+    pub temperature: Option<f64>,
+    last_record: f64,
+    last_save: f64,
+    unsaved: bool,
+}
+impl TemperatureCompensation {
+    /// Starts from a drift that was saved earlier.
+    pub fn new(drift: TemperatureDrift) -> Self {
+        Self {
+            drift,
+            temperature: None,
+            last_record: f64::NEG_INFINITY,
+            last_save: f64::NEG_INFINITY,
+            unsaved: false,
+        }
+    }
+
+    /// Returns the sample with the bias from the temperature removed.
+    pub fn update(&self, frame: JoyconAxisData) -> JoyconAxisData {
+        let Some(temperature) = self.temperature else {
+            return frame;
+        };
+        let [x, y, z] = self.drift.bias(temperature);
+        JoyconAxisData {
+            gyro_x: frame.gyro_x - x,
+            gyro_y: frame.gyro_y - y,
+            gyro_z: frame.gyro_z - z,
+            ..frame
+        }
+    }
+
+    /// Records the bias that is left after `update`, found while lying still at `time`.
+    pub fn record(&mut self, time: f64, remaining_bias: [f64; 3]) {
+        let Some(temperature) = self.temperature else {
+            return;
+        };
+        if time - self.last_record < RECORD_TIME {
+            return;
+        }
+        self.last_record = time;
+
+        let bias = Vector3::from(self.drift.bias(temperature)) + Vector3::from(remaining_bias);
+        let rounded = temperature.round();
+        let points = &mut self.drift.points;
+        match points.iter_mut().find(|p| p.temperature == rounded) {
+            Some(point) => {
+                point.weight = (point.weight + 1.0).min(MAX_WEIGHT);
+                let average = Vector3::from(point.bias);
+                point.bias = (average + (bias - average) / point.weight).into();
+            }
+            None => {
+                points.push(DriftPoint {
+                    temperature: rounded,
+                    bias: bias.into(),
+                    weight: 1.0,
+                });
+                points.sort_by(|a, b| a.temperature.total_cmp(&b.temperature));
+            }
+        }
+        if let Some((reference, slope)) = fit(points) {
+            self.drift.reference = reference;
+            self.drift.slope = slope;
+        }
+        self.unsaved = true;
+    }
+
+    /// Returns the drift when it has changed and the last save was long enough ago.
+    pub fn take_unsaved(&mut self) -> Option<TemperatureDrift> {
+        if self.last_record - self.last_save >= SAVE_TIME {
+            self.take_changed()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the drift when it has changed, however recently it was saved. Used before exiting.
+    pub fn take_changed(&mut self) -> Option<TemperatureDrift> {
+        if self.unsaved {
+            self.unsaved = false;
+            self.last_save = self.last_record;
+            Some(self.drift.clone())
+        } else {
+            None
+        }
+    }
+
+    /// True when a line has been fitted.
+    pub fn compensating(&self) -> bool {
+        self.drift.slope != [0.0; 3]
+    }
+}
+
+/// Reads the temperature in celsius from the reply to reading the two OUT_TEMP registers of the
+/// IMU (subcommand 0x43, starting at register 0x20). The reply is laid out as:
+///
+/// | byte   | content                                   |
+/// |--------|-------------------------------------------|
+/// | 0      | report id 0x21                            |
+/// | 1..13  | timer, battery, buttons, sticks, vibrator |
+/// | 13     | ack                                       |
+/// | 14     | subcommand 0x43                           |
+/// | 15     | first register 0x20                       |
+/// | 16     | register count                            |
+/// | 17, 18 | OUT_TEMP_L, OUT_TEMP_H                    |
+pub fn parse_temperature_reply(reply: &[u8]) -> Option<f64> {
+    match reply.get(14..19)? {
+        [0x43, 0x20, count, low, high] if *count >= 2 => {
+            // 256 per degree on the LSM6DS3TR-C, with 0 at 25 degrees.
+            Some(25.0 + i16::from_le_bytes([*low, *high]) as f64 / 256.0)
+        }
+        _ => None,
+    }
+}
+
+/// Weighted least squares line through the points: the mean temperature and the slope per axis.
+fn fit(points: &[DriftPoint]) -> Option<(f64, [f64; 3])> {
+    let first = points.first()?.temperature;
+    let last = points.last()?.temperature;
+    if last - first < MIN_SPAN {
+        return None;
+    }
+    let total: f64 = points.iter().map(|p| p.weight).sum();
+    let reference = points.iter().map(|p| p.temperature * p.weight).sum::<f64>() / total;
+    let mean = points
+        .iter()
+        .map(|p| Vector3::from(p.bias) * p.weight)
+        .sum::<Vector3<f64>>()
+        / total;
+    let mut covariance = Vector3::zeros();
+    let mut variance = 0.0;
+    for p in points {
+        let dt = p.temperature - reference;
+        covariance += (Vector3::from(p.bias) - mean) * dt * p.weight;
+        variance += dt * dt * p.weight;
+    }
+    let slope = covariance / variance;
+    (slope.amax() < MAX_SLOPE).then(|| (reference, slope.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(gyro: [f64; 3]) -> JoyconAxisData {
+        JoyconAxisData {
+            accel_x: 0.0,
+            accel_y: 0.0,
+            accel_z: 1.0,
+            gyro_x: gyro[0],
+            gyro_y: gyro[1],
+            gyro_z: gyro[2],
+            time: 0.0,
+        }
+    }
+
+    #[test]
+    fn fits_drift_while_warming_up() {
+        let bias = |t: f64| [0.01 + 0.001 * (t - 25.0), -0.02, -0.0005 * (t - 25.0)];
+        let mut compensation = TemperatureCompensation::new(TemperatureDrift::default());
+        // Warms up from 25 to 35 degrees over 10 minutes, lying still the whole time. The bias that
+        // is left after the compensation is what the gyro bias filter would find.
+        for i in 0..20 {
+            let temperature = 25.0 + i as f64 * 0.5;
+            compensation.temperature = Some(temperature);
+            let remaining = compensation.update(frame(bias(temperature)));
+            compensation.record(
+                i as f64 * 30.0,
+                [remaining.gyro_x, remaining.gyro_y, remaining.gyro_z],
+            );
+        }
+        assert!(compensation.compensating());
+        let drift = compensation.take_unsaved().unwrap();
+        assert!(compensation.take_unsaved().is_none());
+        assert!((drift.slope[0] - 0.001).abs() < 1e-4, "{:?}", drift.slope);
+        assert!(drift.slope[1].abs() < 1e-9, "{:?}", drift.slope);
+        assert!((drift.slope[2] + 0.0005).abs() < 1e-4, "{:?}", drift.slope);
+
+        // The change between two temperatures is removed, the rest is left to the bias filter.
+        let mut at = |temperature| {
+            compensation.temperature = Some(temperature);
+            compensation.update(frame(bias(temperature)))
+        };
+        let (cold, warm) = (at(26.0), at(34.0));
+        assert!((cold.gyro_x - warm.gyro_x).abs() < 1e-3);
+        assert!((cold.gyro_z - warm.gyro_z).abs() < 1e-3);
+
+        // Later points are saved together.
+        compensation.record(600.0, [0.0; 3]);
+        assert!(compensation.take_unsaved().is_none());
+        compensation.record(870.0, [0.0; 3]);
+        assert!(compensation.take_unsaved().is_some());
+        // Unless Wrangler is exiting.
+        compensation.record(900.0, [0.0; 3]);
+        assert!(compensation.take_unsaved().is_none());
+        assert!(compensation.take_changed().is_some());
+        assert!(compensation.take_changed().is_none());
+    }
+
+    #[test]
+    fn parses_temperature_reply() {
+        let mut reply = [0u8; 49];
+        reply[..19].copy_from_slice(&[
+            0x21, 0x5a, 0x8e, 0x00, 0x00, 0x00, 0x0b, 0x78, 0x7a, 0x00, 0x08, 0x80, 0x0c, 0xc0,
+            0x43, 0x20, 0x02, 0x80, 0x05,
+        ]);
+        // 0x0580 is 5.5 degrees above 25.
+        assert_eq!(parse_temperature_reply(&reply), Some(30.5));
+        reply[17..19].copy_from_slice(&(-512i16).to_le_bytes());
+        assert_eq!(parse_temperature_reply(&reply), Some(23.0));
+        // A reply to another subcommand.
+        reply[14] = 0x10;
+        assert_eq!(parse_temperature_reply(&reply), None);
+        assert_eq!(parse_temperature_reply(&reply[..16]), None);
+    }
+
+    #[test]
+    fn unchanged_without_temperature() {
+        let mut compensation = TemperatureCompensation::new(TemperatureDrift {
+            points: vec![],
+            reference: 30.0,
+            slope: [0.001; 3],
+        });
+        compensation.record(0.0, [0.01; 3]);
+        assert!(compensation.take_unsaved().is_none());
+        assert_eq!(compensation.update(frame([0.1; 3])), frame([0.1; 3]));
+    }
+}
@@ -1,8 +1,12 @@
-use std::{sync::mpsc, thread, time::Duration};
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime},
+};
 
 use super::{
     communication::{ChannelData, ChannelInfo},
-    imu::JoyconAxisData,
+    imu::{timestamp, JoyconAxisData},
     Battery, JoyconDesign, JoyconDesignType,
 };
 
@@ -17,6 +21,7 @@ fn spawn_test(tx: mpsc::Sender<ChannelData>, color: String, sn: String, z_change
     .unwrap();
 
     loop {
+        let now = timestamp(SystemTime::now());
         let d = JoyconAxisData {
             accel_x: 0.0,
             accel_y: -1.0,
@@ -24,10 +29,16 @@ fn spawn_test(tx: mpsc::Sender<ChannelData>, color: String, sn: String, z_change
             gyro_x: 0.0,
             gyro_y: 0.0,
             gyro_z: z_change,
+            time: now,
         };
+        // The three samples of a report are 5ms apart, like on a real Joy-Con.
+        let frames = [-0.010, -0.005, 0.0].map(|offset| JoyconAxisData {
+            time: now + offset,
+            ..d
+        });
         tx.send(ChannelData {
             serial_number: sn.clone(),
-            info: ChannelInfo::ImuData([d, d, d]),
+            info: ChannelInfo::ImuData(frames),
         })
         .unwrap();
 
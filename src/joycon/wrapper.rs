@@ -1,38 +1,77 @@
-use std::{env, sync::mpsc};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
 
-use crate::settings;
+use crate::{api, cli, settings};
 
 #[cfg(target_os = "linux")]
 use super::linux_integration;
 use super::{
-    communication::ServerStatus, spawn_thread, test_integration::test_controllers, Communication,
-    Status,
+    communication::{ChannelData, ChannelInfo, ServerStatus},
+    recorder,
+    replay_integration::replay,
+    spawn_thread,
+    test_integration::test_controllers,
+    Communication, Status,
 };
 
 pub struct Wrapper {
     status_rx: mpsc::Receiver<Vec<Status>>,
     server_rx: mpsc::Receiver<ServerStatus>,
+    discovered_rx: mpsc::Receiver<SocketAddr>,
+    tx: mpsc::Sender<ChannelData>,
+    communication: Option<JoinHandle<()>>,
 }
 impl Wrapper {
     pub fn new(settings: settings::Handler) -> Self {
         let (status_tx, status_rx) = mpsc::channel();
         let (server_tx, server_rx) = mpsc::channel();
+        let (discovered_tx, discovered_rx) = mpsc::channel();
         let (tx, rx) = mpsc::channel();
 
-        {
+        // When recording, everything passes through the recorder on the way to Communication.
+        let rx = match cli::value("--record") {
+            Some(path) => {
+                let (recorded_tx, recorded_rx) = mpsc::channel();
+                std::thread::spawn(move || recorder::tee(rx, recorded_tx, path.into()));
+                recorded_rx
+            }
+            None => rx,
+        };
+
+        let shared = Arc::new(api::Shared::default());
+        let communication = {
             let settings = settings.clone();
+            let shared = shared.clone();
             std::thread::spawn(move || {
-                Communication::start(rx, status_tx, server_tx, settings);
-            });
+                Communication::start(rx, status_tx, server_tx, discovered_tx, shared, settings);
+            })
+        };
+
+        {
+            let tx = tx.clone();
+            let settings = settings.clone();
+            std::thread::spawn(move || api::spawn_thread(settings, tx, shared));
         }
 
         {
             let tx = tx.clone();
-            if env::args().any(|a| &a == "test") {
+            if cli::flag("test") {
                 std::thread::spawn(move || test_controllers(tx));
             }
         }
 
+        if let Some(path) = cli::value("--replay") {
+            let tx = tx.clone();
+            let speed = cli::value("--replay-speed")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1.0);
+            std::thread::spawn(move || replay(tx, PathBuf::from(path), speed));
+        }
+
         // evdev integration
         #[cfg(target_os = "linux")]
         {
@@ -41,11 +80,17 @@ impl Wrapper {
             std::thread::spawn(move || linux_integration::spawn_thread(tx, settings));
         }
 
-        std::thread::spawn(move || spawn_thread(tx, settings));
+        {
+            let tx = tx.clone();
+            std::thread::spawn(move || spawn_thread(tx, settings));
+        }
 
         Self {
             status_rx,
             server_rx,
+            discovered_rx,
+            tx,
+            communication: Some(communication),
         }
     }
     pub fn poll_status(&self) -> Option<Vec<Status>> {
@@ -54,4 +99,18 @@ impl Wrapper {
     pub fn poll_server(&self) -> Option<ServerStatus> {
         self.server_rx.try_iter().last()
     }
+    pub fn poll_discovered(&self) -> Vec<SocketAddr> {
+        self.discovered_rx.try_iter().collect()
+    }
+    /// Sends a message to the communication thread, as if it came from a controller.
+    pub fn send(&self, data: ChannelData) {
+        self.tx.send(data).ok();
+    }
+    /// Lets the communication thread save what it has learned, and waits for it to stop.
+    pub fn shutdown(&mut self) {
+        self.send(ChannelData::new(String::new(), ChannelInfo::Shutdown));
+        if let Some(communication) = self.communication.take() {
+            communication.join().ok();
+        }
+    }
 }
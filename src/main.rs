@@ -3,25 +3,33 @@
 use iced::{
     time,
     widget::{
-        button, checkbox, container, horizontal_space, scrollable, slider, text, text_input,
-        Column, Container, Row, Scrollable, Space, Svg,
+        button, checkbox, container, horizontal_space, pick_list, radio, scrollable, slider, text,
+        text_input, Column, Container, Row, Scrollable, Space, Svg,
     },
     window, Alignment, Color, Element, Font, Length, Size, Subscription, Task as Command,
 };
 
 use circle::circle;
 use iced_aw::Wrap;
-use joycon::{Battery, DeviceStatus, ServerStatus};
+use joycon::{Battery, ChannelData, ChannelInfo, DeviceStatus, ResetKind, ServerStatus};
 use needle::Needle;
-use settings::WranglerSettings;
+use settings::{
+    ButtonAction, ButtonMapping, FilterKind, MountingPreset, TapMapping, WranglerSettings,
+};
 use std::{
+    env,
+    f64::consts::TAU,
     io::{
         self,
         prelude::{Read, Write},
     },
     net::SocketAddr,
+    path::PathBuf,
     time::{Duration, Instant},
 };
+mod api;
+mod cli;
+mod headless;
 mod joycon;
 mod steam_blacklist;
 use steam_blacklist as blacklist;
@@ -31,6 +39,9 @@ mod settings;
 mod style;
 mod update;
 
+/// How long there is to get into a T-pose before the mounting is captured.
+const TPOSE_DELAY: Duration = Duration::from_secs(5);
+
 const WINDOW_SIZE: Size = Size {
     width: 980.0,
     height: 700.0,
@@ -40,6 +51,25 @@ pub const ICONS: &[u8] = include_bytes!("../assets/icons.ttf");
 pub const ICON: &[u8; 16384] = include_bytes!("../assets/icon_64.rgba8");
 
 pub fn main() -> iced::Result {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match cli::parse(&args) {
+        Ok(Some(command)) => {
+            cli::run(command);
+            return Ok(());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            println!("\x1b[0;31m[ERROR]\x1b[0m {e}");
+            std::process::exit(2);
+        }
+    }
+    if cli::flag("--headless") {
+        if let Err(e) = headless::run(cli::value("--log-file").map(PathBuf::from)) {
+            println!("\x1b[0;31m[ERROR]\x1b[0m Could not run headless: {e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     /*
     let rgba8 = image_rs::io::Reader::open("assets/icon.png").unwrap().decode().unwrap().to_rgba8();
     std::fs::write("assets/icon_64.rgba8", rgba8.into_raw());
@@ -48,6 +78,8 @@ pub fn main() -> iced::Result {
         size: WINDOW_SIZE,
         min_size: Some(WINDOW_SIZE),
         icon: window::icon::from_rgba(ICON.to_vec(), 64, 64).ok(),
+        // Closing goes through `Message::CloseRequested`, to save before exiting.
+        exit_on_close_request: false,
         ..window::Settings::default()
     };
     let run = iced::application("SlimeVR Wrangler", MainState::update, MainState::view)
@@ -80,8 +112,163 @@ enum Message {
     BlacklistFixPressed,
     JoyconRotate(String, bool),
     JoyconScale(String, f64),
+    JoyconFilter(String, FilterKind),
+    JoyconPreset(String, MountingPreset),
+    JoyconReset(String, ResetKind),
+    TposeStart,
     SettingsResetToggled(bool),
+    MappingAdd,
+    MappingRemove(usize),
+    MappingChange(usize, ButtonMapping),
+    TapsToggled(bool),
+    TapThreshold(f64),
+    TapGap(f64),
+    TapMappingAdd,
+    TapMappingRemove(usize),
+    TapMappingChange(usize, TapMapping),
     SettingsIdsToggled(bool),
+    SettingsBundleToggled(bool),
+    SettingsSeparateToggled(bool),
+    SettingsDiscoverToggled(bool),
+    SettingsApiToggled(bool),
+    SettingsOscToggled(bool),
+    OscChange(OscField, String),
+    SettingsVmcToggled(bool),
+    VmcTargetChange(String),
+    JoyconVmcName(String, String),
+    ServerPicked(SocketAddr),
+    ScaleWizardOpen(String),
+    ScaleWizardAxis(usize),
+    ScaleWizardTurns(u32),
+    ScaleWizardStart,
+    ScaleWizardDone,
+    ScaleWizardClose,
+    AccelWizardOpen(String),
+    AccelWizardCapture,
+    AccelWizardClose,
+    CloseRequested,
+}
+
+/// Number of taps a tap mapping can have.
+const TAP_COUNTS: [u32; 4] = [2, 3, 4, 5];
+/// Seconds a button mapping can be held for.
+const HOLD_TIMES: [f64; 6] = [0.0, 0.5, 1.0, 2.0, 3.0, 5.0];
+
+#[derive(Debug, Clone, Copy)]
+enum OscField {
+    Target,
+    Rotation,
+    Euler,
+    Button,
+}
+
+/// Finds the gyro scale of one axis, by turning a Joy-Con a known number of times.
+struct ScaleWizard {
+    serial_number: String,
+    axis: usize,
+    turns: u32,
+    measuring: bool,
+    result: Option<Result<String, String>>,
+}
+impl ScaleWizard {
+    const AXES: [&'static str; 3] = ["X", "Y", "Z"];
+    const TURNS: [u32; 5] = [1, 2, 3, 5, 10];
+
+    fn new(serial_number: String) -> Self {
+        Self {
+            serial_number,
+            // Lying flat on a table and turning it is the easiest.
+            axis: 2,
+            turns: 3,
+            measuring: false,
+            result: None,
+        }
+    }
+    fn measure(&self, joycon: &joycon::Wrapper, axis: Option<usize>) {
+        joycon.send(ChannelData::new(
+            self.serial_number.clone(),
+            ChannelInfo::MeasureAngle(axis),
+        ));
+    }
+    /// Works out the new scale from the angle that was measured with the current scale.
+    fn finish(
+        &self,
+        measured_angle: Option<f64>,
+        settings: &settings::Handler,
+    ) -> Result<String, String> {
+        let measured_turns = measured_angle
+            .ok_or("Nothing was measured, is the Joy-Con still connected?")?
+            .abs()
+            / TAU;
+        let current = settings.load().joycon_scale_get(&self.serial_number)[self.axis];
+        let scale = current * self.turns as f64 / measured_turns;
+        if !settings::GYRO_SCALE_RANGE.contains(&scale) {
+            return Err(format!(
+                "Measured {measured_turns:.2} turns, which is too far off. Try again, and make sure to turn it exactly {} times.",
+                self.turns
+            ));
+        }
+        settings.change(|ws| {
+            ws.joycon_axis_scale_set(self.serial_number.clone(), self.axis, scale);
+        });
+        Ok(format!(
+            "Measured {measured_turns:.2} turns. The scale of the {} axis is now {scale:.3}.",
+            Self::AXES[self.axis]
+        ))
+    }
+}
+
+/// Calibrates the accelerometer, by laying a Joy-Con still on each of its six sides.
+struct AccelWizard {
+    serial_number: String,
+    faces: joycon::SixFace,
+    result: Option<Result<String, String>>,
+    finished: bool,
+}
+impl AccelWizard {
+    fn new(serial_number: String) -> Self {
+        Self {
+            serial_number,
+            faces: joycon::SixFace::default(),
+            result: None,
+            finished: false,
+        }
+    }
+    /// The faces have to be measured without the old calibration, which stays saved until the
+    /// new one is done.
+    fn measure_raw(&self, joycon: &joycon::Wrapper, raw: bool) {
+        joycon.send(ChannelData::new(
+            self.serial_number.clone(),
+            ChannelInfo::RawAccel(raw),
+        ));
+    }
+    fn capture(
+        &mut self,
+        status: Option<&joycon::Status>,
+        joycon: &joycon::Wrapper,
+        settings: &settings::Handler,
+    ) -> Result<String, String> {
+        let status = status.ok_or("The Joy-Con is not connected.")?;
+        if !status.still {
+            return Err("Let it lie still for a moment first.".into());
+        }
+        let face = self.faces.add(status.acc_average)?;
+        match self.faces.fit() {
+            None => Ok(format!("Captured side {}.", joycon::FACES[face])),
+            Some(Ok(calibration)) => {
+                settings.change(|ws| {
+                    ws.joycon_accel_calibration_set(self.serial_number.clone(), calibration)
+                });
+                self.measure_raw(joycon, false);
+                self.finished = true;
+                Ok("All sides captured, the calibration is saved.".into())
+            }
+            Some(Err(e)) => {
+                self.faces = joycon::SixFace::default();
+                Err(e.into())
+            }
+        }
+    }
 }
 
 #[derive(Default)]
@@ -91,7 +278,14 @@ struct MainState {
     search_dots: usize,
     settings_show: bool,
     server_connected: ServerStatus,
-    server_address: String,
+    /// The discovered server that is in use.
+    found_server: Option<SocketAddr>,
+    discovered_servers: Vec<SocketAddr>,
+    scale_wizard: Option<ScaleWizard>,
+    accel_wizard: Option<AccelWizard>,
+    /// When the mounting will be captured from a T-pose.
+    tpose_at: Option<Instant>,
+    tpose_result: Option<String>,
 
     settings: settings::Handler,
     update_found: Option<String>,
@@ -101,7 +295,6 @@ impl MainState {
     fn new() -> (Self, Command<Message>) {
         let mut new = Self::default();
         new.joycon = Some(joycon::Wrapper::new(new.settings.clone()));
-        new.server_address = format!("{}", new.settings.load().get_socket_address());
         (
             new,
             Command::batch(vec![
@@ -116,7 +309,13 @@ impl MainState {
             Message::SettingsPressed => {
                 self.settings_show = !self.settings_show;
             }
-            Message::Tick(_time) => {
+            Message::CloseRequested => {
+                if let Some(ji) = self.joycon.as_mut() {
+                    ji.shutdown();
+                }
+                return iced::exit();
+            }
+            Message::Tick(time) => {
                 if let Some(ref ji) = self.joycon {
                     if let Some(res) = ji.poll_status() {
                         self.joycon_boxes.statuses = res;
@@ -124,6 +323,19 @@ impl MainState {
                     if let Some(connected) = ji.poll_server() {
                         self.server_connected = connected;
                     }
+                    for server in ji.poll_discovered() {
+                        // The first server to answer is the one that gets used.
+                        if self.found_server.is_none() && self.settings.load().discover_server {
+                            self.found_server = Some(server);
+                        }
+                        if !self.discovered_servers.contains(&server) {
+                            self.discovered_servers.push(server);
+                        }
+                    }
+                }
+                if matches!(self.tpose_at, Some(at) if time >= at) {
+                    self.tpose_at = None;
+                    self.capture_tpose();
                 }
             }
             Message::Dot(_time) => {
@@ -156,24 +368,240 @@ impl MainState {
                 self.settings
                     .change(|ws| ws.joycon_scale_set(serial_number, scale));
             }
+            Message::JoyconPreset(serial_number, preset) => {
+                self.settings
+                    .change(|ws| ws.joycon_preset_set(serial_number, preset));
+            }
+            Message::TposeStart => {
+                self.tpose_at = Some(Instant::now() + TPOSE_DELAY);
+                self.tpose_result = None;
+            }
+            Message::JoyconFilter(serial_number, kind) => {
+                self.settings
+                    .change(|ws| ws.joycon_filter_set(serial_number, kind));
+            }
             Message::SettingsResetToggled(new) => {
                 self.settings.change(|ws| ws.send_reset = new);
             }
+            Message::MappingAdd => {
+                self.settings
+                    .change(|ws| ws.button_mappings.push(ButtonMapping::default()));
+            }
+            Message::MappingRemove(i) => {
+                self.settings.change(|ws| {
+                    if i < ws.button_mappings.len() {
+                        ws.button_mappings.remove(i);
+                    }
+                });
+            }
+            Message::MappingChange(i, mapping) => {
+                self.settings.change(|ws| {
+                    if let Some(slot) = ws.button_mappings.get_mut(i) {
+                        *slot = mapping;
+                    }
+                });
+            }
+            Message::TapsToggled(new) => {
+                self.settings.change(|ws| ws.taps.enabled = new);
+            }
+            Message::TapThreshold(threshold) => {
+                self.settings.change(|ws| ws.taps.threshold = threshold);
+            }
+            Message::TapGap(max_gap) => {
+                self.settings.change(|ws| ws.taps.max_gap = max_gap);
+            }
+            Message::TapMappingAdd => {
+                self.settings
+                    .change(|ws| ws.taps.mappings.push(TapMapping::default()));
+            }
+            Message::TapMappingRemove(i) => {
+                self.settings.change(|ws| {
+                    if i < ws.taps.mappings.len() {
+                        ws.taps.mappings.remove(i);
+                    }
+                });
+            }
+            Message::TapMappingChange(i, mapping) => {
+                self.settings.change(|ws| {
+                    if let Some(slot) = ws.taps.mappings.get_mut(i) {
+                        *slot = mapping;
+                    }
+                });
+            }
+            Message::JoyconReset(serial_number, kind) => {
+                if let Some(ji) = &self.joycon {
+                    ji.send(ChannelData::new(
+                        serial_number,
+                        ChannelInfo::ResetDevice(kind),
+                    ));
+                }
+            }
             Message::SettingsIdsToggled(new) => {
                 self.settings.change(|ws| ws.keep_ids = new);
             }
+            Message::SettingsBundleToggled(new) => {
+                self.settings.change(|ws| ws.bundle_packets = new);
+            }
+            Message::SettingsSeparateToggled(new) => {
+                self.settings.change(|ws| ws.separate_trackers = new);
+            }
+            Message::SettingsDiscoverToggled(new) => {
+                self.settings.change(|ws| ws.discover_server = new);
+                self.found_server = None;
+            }
+            Message::SettingsApiToggled(new) => {
+                self.settings.change(|ws| ws.api_enabled = new);
+            }
+            Message::SettingsOscToggled(new) => {
+                self.settings.change(|ws| ws.osc.enabled = new);
+            }
+            Message::OscChange(field, value) => {
+                self.settings.change(|ws| {
+                    *match field {
+                        OscField::Target => &mut ws.osc.target,
+                        OscField::Rotation => &mut ws.osc.rotation_address,
+                        OscField::Euler => &mut ws.osc.euler_address,
+                        OscField::Button => &mut ws.osc.button_address,
+                    } = value;
+                });
+            }
+            Message::SettingsVmcToggled(new) => {
+                self.settings.change(|ws| ws.vmc.enabled = new);
+            }
+            Message::VmcTargetChange(value) => {
+                self.settings.change(|ws| ws.vmc.target = value);
+            }
+            Message::JoyconVmcName(serial_number, name) => {
+                self.settings
+                    .change(|ws| ws.joycon_vmc_name_set(serial_number, name));
+            }
+            Message::ServerPicked(server) => {
+                self.settings.change(|ws| {
+                    ws.address = format!("{server}");
+                    ws.discover_server = false;
+                });
+            }
+            Message::ScaleWizardOpen(serial_number) => {
+                self.scale_wizard_close();
+                self.scale_wizard = Some(ScaleWizard::new(serial_number));
+            }
+            Message::ScaleWizardAxis(axis) => {
+                if let Some(wizard) = &mut self.scale_wizard {
+                    wizard.axis = axis;
+                }
+            }
+            Message::ScaleWizardTurns(turns) => {
+                if let Some(wizard) = &mut self.scale_wizard {
+                    wizard.turns = turns;
+                }
+            }
+            Message::ScaleWizardStart => {
+                if let (Some(wizard), Some(ji)) = (&mut self.scale_wizard, &self.joycon) {
+                    wizard.measure(ji, Some(wizard.axis));
+                    wizard.measuring = true;
+                    wizard.result = None;
+                }
+            }
+            Message::ScaleWizardDone => {
+                let measured_angle = self.measured_angle();
+                if let (Some(wizard), Some(ji)) = (&mut self.scale_wizard, &self.joycon) {
+                    wizard.measure(ji, None);
+                    wizard.measuring = false;
+                    wizard.result = Some(wizard.finish(measured_angle, &self.settings));
+                }
+            }
+            Message::ScaleWizardClose => {
+                self.scale_wizard_close();
+            }
+            Message::AccelWizardOpen(serial_number) => {
+                self.accel_wizard_close();
+                let wizard = AccelWizard::new(serial_number);
+                if let Some(ji) = &self.joycon {
+                    wizard.measure_raw(ji, true);
+                }
+                self.accel_wizard = Some(wizard);
+            }
+            Message::AccelWizardCapture => {
+                if let (Some(wizard), Some(ji)) = (&mut self.accel_wizard, &self.joycon) {
+                    let status = self
+                        .joycon_boxes
+                        .statuses
+                        .iter()
+                        .find(|s| s.serial_number == wizard.serial_number);
+                    wizard.result = Some(wizard.capture(status, ji, &self.settings));
+                }
+            }
+            Message::AccelWizardClose => {
+                self.accel_wizard_close();
+            }
         }
         Command::none()
     }
 
+    /// Sets the mounting of every Joy-Con so that it points up in the current pose.
+    fn capture_tpose(&mut self) {
+        // Devices that aren't sending IMU data have no current rotation to capture.
+        let statuses: Vec<_> = self
+            .joycon_boxes
+            .statuses
+            .iter()
+            .filter(|s| matches!(s.status, DeviceStatus::Healthy | DeviceStatus::LaggyIMU))
+            .collect();
+        self.settings.change(|ws| {
+            for status in &statuses {
+                let [w, x, y, z] = status.raw_quat;
+                let rotation = nalgebra::UnitQuaternion::from_quaternion(
+                    nalgebra::Quaternion::new(w, x, y, z),
+                );
+                ws.joycon_mounting_set(
+                    status.serial_number.clone(),
+                    settings::mounting_from_tpose(rotation),
+                );
+            }
+        });
+        self.tpose_result = Some(format!("Mounting set for {} Joy-Cons.", statuses.len()));
+    }
+
+    fn measured_angle(&self) -> Option<f64> {
+        let wizard = self.scale_wizard.as_ref()?;
+        self.joycon_boxes
+            .statuses
+            .iter()
+            .find(|s| s.serial_number == wizard.serial_number)
+            .and_then(|s| s.measured_angle)
+    }
+
+    fn scale_wizard_close(&mut self) {
+        if let (Some(wizard), Some(ji)) = (self.scale_wizard.take(), &self.joycon) {
+            if wizard.measuring {
+                wizard.measure(ji, None);
+            }
+        }
+    }
+
+    fn accel_wizard_close(&mut self) {
+        if let (Some(wizard), Some(ji)) = (self.accel_wizard.take(), &self.joycon) {
+            if !wizard.finished {
+                wizard.measure_raw(ji, false);
+            }
+        }
+    }
+
     fn subscription(&self) -> Subscription<Message> {
         Subscription::batch(vec![
             time::every(Duration::from_millis(500)).map(Message::Dot),
             time::every(Duration::from_millis(50)).map(Message::Tick),
+            window::close_requests().map(|_| Message::CloseRequested),
         ])
     }
 
     fn view(&self) -> Element<Message> {
+        let server_address = if self.settings.load().discover_server {
+            self.found_server
+                .map_or("searching local network".into(), |s| format!("{s}"))
+        } else {
+            format!("{}", self.settings.load().get_socket_address())
+        };
         let mut app = Column::new().push(top_bar(self.update_found.clone()));
 
         if self.blacklist_info.visible() {
@@ -182,7 +610,7 @@ impl MainState {
 
         app.push(
             if self.settings_show {
-                container(self.settings_screen()).padding(20)
+                container(scrollable(self.settings_screen()).height(Length::Fill)).padding(20)
             } else {
                 container(self.joycon_screen())
             }
@@ -193,7 +621,7 @@ impl MainState {
         .push(bottom_bar(
             self.server_connected,
             &".".repeat(self.search_dots),
-            &self.server_address,
+            &server_address,
         ))
         .into()
     }
@@ -202,7 +630,37 @@ impl MainState {
         let grid = boxes.into_iter().fold(Wrap::new(), |wrap, bax| {
             wrap.push(container(bax).padding(10))
         });
-        let list = Column::new().padding(10).width(Length::Fill).push(grid);
+        let tpose_text = match (self.tpose_at, &self.tpose_result) {
+            (Some(at), _) => format!(
+                "Stand in a T-pose! Capturing in {} seconds...",
+                at.saturating_duration_since(Instant::now()).as_secs() + 1
+            ),
+            (None, Some(result)) => result.clone(),
+            (None, None) => {
+                "Or set the mounting of all Joy-Cons by standing in a T-pose, with them pointing up."
+                    .into()
+            }
+        };
+        let tpose = Row::new()
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .push(
+                button("Set mounting from T-pose")
+                    .on_press_maybe(self.tpose_at.is_none().then_some(Message::TposeStart))
+                    .style(style::button_primary),
+            )
+            .push(text(tpose_text));
+        let mut list = Column::new()
+            .padding(10)
+            .width(Length::Fill)
+            .push(container(tpose).padding(10));
+        if let Some(wizard) = &self.scale_wizard {
+            list = list.push(container(scale_wizard(wizard, self.measured_angle())).padding(10));
+        }
+        if let Some(wizard) = &self.accel_wizard {
+            list = list.push(container(accel_wizard(wizard)).padding(10));
+        }
+        let list = list.push(grid);
 
         let list = list.push(
             container(text(format!(
@@ -219,16 +677,34 @@ impl MainState {
         Column::new()
             .spacing(20)
             .push(address(&self.settings.load().address))
+            .push(discovery(
+                self.settings.load().discover_server,
+                &self.discovered_servers,
+            ))
+            .push(button_mappings(&self.settings.load()))
+            .push(tap_settings(&self.settings.load().taps))
             .push(checkbox(
-                "Send yaw reset command to SlimeVR Server after B or UP button press.",
-                self.settings.load().send_reset).on_toggle(
-                Message::SettingsResetToggled)
-            )
-            .push(checkbox(
-                "Save mounting location on server. Requires SlimeVR Server v0.6.1 or newer. Restart Wrangler after changing this.",
+                "Save mounting location on server. Requires SlimeVR Server v0.6.1 or newer.",
                 self.settings.load().keep_ids).on_toggle(
                 Message::SettingsIdsToggled,
             ))
+            .push(checkbox(
+                "Combine tracker data into one packet per update, if the SlimeVR Server supports it. Useful when the server runs on another computer.",
+                self.settings.load().bundle_packets).on_toggle(
+                Message::SettingsBundleToggled,
+            ))
+            .push(checkbox(
+                "Show each controller as its own tracker on the SlimeVR Server. Restart Wrangler after changing this.",
+                self.settings.load().separate_trackers).on_toggle(
+                Message::SettingsSeparateToggled,
+            ))
+            .push(checkbox(
+                format!("Let other programs on this computer read tracker status and control Wrangler, on port {}.", self.settings.load().api_port),
+                self.settings.load().api_enabled).on_toggle(
+                Message::SettingsApiToggled,
+            ))
+            .push(osc_settings(&self.settings.load().osc))
+            .push(vmc_settings(&self.settings.load().vmc))
     }
 }
 
@@ -242,8 +718,7 @@ fn address<'a>(input_value: &str) -> Column<'a, Message> {
         .spacing(10)
         .align_y(Alignment::Center)
         .push("SlimeVR Server address:")
-        .push(address)
-        .push("Restart Wrangler after changing this.");
+        .push(address);
     let mut allc = Column::new().push(address_row).spacing(10);
 
     if input_value.parse::<SocketAddr>().is_err() {
@@ -256,6 +731,383 @@ fn address<'a>(input_value: &str) -> Column<'a, Message> {
     }
     allc
 }
+fn button_mappings<'a>(settings: &WranglerSettings) -> Column<'a, Message> {
+    let mut allc = Column::new().spacing(10).push(text(
+        "Button mappings. Buttons that are held together are joined with +, like \"l+up\". \
+        The resets are done on all trackers, the other actions are sent to SlimeVR Server.",
+    ));
+    for (i, mapping) in settings.button_mappings.iter().enumerate() {
+        // Each input sends the whole mapping with its field changed.
+        let changed = move |edit: ButtonMapping| Message::MappingChange(i, edit);
+        let (m1, m2, m3, m4) = (
+            mapping.clone(),
+            mapping.clone(),
+            mapping.clone(),
+            mapping.clone(),
+        );
+        let mut row = Row::new()
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .push(
+                text_input("buttons", &mapping.buttons)
+                    .on_input(move |buttons| {
+                        changed(ButtonMapping {
+                            buttons,
+                            ..m1.clone()
+                        })
+                    })
+                    .width(Length::Fixed(120.0))
+                    .padding(10),
+            )
+            .push(text("Hold (s):"))
+            .push(
+                pick_list(HOLD_TIMES, Some(mapping.hold), move |hold| {
+                    changed(ButtonMapping { hold, ..m2.clone() })
+                })
+                .padding(5),
+            )
+            .push(
+                pick_list(ButtonAction::ALL, Some(mapping.action), move |action| {
+                    changed(ButtonMapping {
+                        action,
+                        ..m3.clone()
+                    })
+                })
+                .padding(5),
+            );
+        if mapping.action == ButtonAction::Osc {
+            row = row.push(
+                text_input("/address", &mapping.osc_address)
+                    .on_input(move |osc_address| {
+                        changed(ButtonMapping {
+                            osc_address,
+                            ..m4.clone()
+                        })
+                    })
+                    .width(Length::Fixed(250.0))
+                    .padding(10),
+            );
+        }
+        allc = allc.push(
+            row.push(
+                button("Remove")
+                    .on_press(Message::MappingRemove(i))
+                    .style(style::button_primary),
+            ),
+        );
+        if let Err(e) = joycon::parse_combo(&mapping.buttons) {
+            allc = allc.push(container(text(e)).style(style::text_yellow));
+        }
+    }
+    allc.push(
+        button("Add mapping")
+            .on_press(Message::MappingAdd)
+            .style(style::button_primary),
+    )
+    .push(
+        checkbox(
+            "Send the resets to SlimeVR Server as well.",
+            settings.send_reset,
+        )
+        .on_toggle(Message::SettingsResetToggled),
+    )
+}
+fn tap_settings<'a>(taps: &settings::Taps) -> Column<'a, Message> {
+    let mut allc = Column::new().spacing(10).push(
+        checkbox(
+            "Run actions by tapping on a Joy-Con, for when its buttons can't be reached.",
+            taps.enabled,
+        )
+        .on_toggle(Message::TapsToggled),
+    );
+    if !taps.enabled {
+        return allc;
+    }
+    let slider_row = |label: String, range, value, on_change: fn(f64) -> Message| {
+        Row::new()
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .push(text(label).width(Length::Fixed(200.0)))
+            .push(
+                slider(range, value, on_change)
+                    .step(0.05)
+                    .width(Length::Fixed(300.0)),
+            )
+    };
+    allc = allc
+        .push(slider_row(
+            format!("Tap strength: {:.2} G", taps.threshold),
+            settings::TAP_THRESHOLD_RANGE,
+            taps.threshold,
+            Message::TapThreshold,
+        ))
+        .push(slider_row(
+            format!("Time between taps: {:.2} s", taps.max_gap),
+            settings::TAP_GAP_RANGE,
+            taps.max_gap,
+            Message::TapGap,
+        ));
+    for (i, mapping) in taps.mappings.iter().enumerate() {
+        let changed = move |edit: TapMapping| Message::TapMappingChange(i, edit);
+        let (m1, m2, m3) = (mapping.clone(), mapping.clone(), mapping.clone());
+        let mut row = Row::new()
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .push(
+                pick_list(TAP_COUNTS, Some(mapping.taps), move |taps| {
+                    changed(TapMapping { taps, ..m1.clone() })
+                })
+                .padding(5),
+            )
+            .push(text("taps:"))
+            .push(
+                pick_list(ButtonAction::ALL, Some(mapping.action), move |action| {
+                    changed(TapMapping {
+                        action,
+                        ..m2.clone()
+                    })
+                })
+                .padding(5),
+            );
+        if mapping.action == ButtonAction::Osc {
+            row = row.push(
+                text_input("/address", &mapping.osc_address)
+                    .on_input(move |osc_address| {
+                        changed(TapMapping {
+                            osc_address,
+                            ..m3.clone()
+                        })
+                    })
+                    .width(Length::Fixed(250.0))
+                    .padding(10),
+            );
+        }
+        allc = allc.push(
+            row.push(
+                button("Remove")
+                    .on_press(Message::TapMappingRemove(i))
+                    .style(style::button_primary),
+            ),
+        );
+    }
+    allc.push(
+        button("Add tap mapping")
+            .on_press(Message::TapMappingAdd)
+            .style(style::button_primary),
+    )
+}
+fn osc_settings<'a>(osc: &settings::Osc) -> Column<'a, Message> {
+    let field = |label: &'a str, value: &str, field: OscField| {
+        Row::new()
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .push(text(label).width(Length::Fixed(150.0)))
+            .push(
+                text_input("", value)
+                    .on_input(move |v| Message::OscChange(field, v))
+                    .width(Length::Fixed(300.0))
+                    .padding(10),
+            )
+    };
+    let mut allc = Column::new().spacing(10).push(
+        checkbox(
+            "Send tracker rotations and button presses over OSC, for VRChat and other OSC programs.",
+            osc.enabled,
+        )
+        .on_toggle(Message::SettingsOscToggled),
+    );
+    if osc.enabled {
+        allc = allc.push(field("OSC target:", &osc.target, OscField::Target));
+        if osc.target.parse::<SocketAddr>().is_err() {
+            allc = allc.push(
+                container(text("OSC target is not a valid ip with port number!"))
+                    .style(style::text_yellow),
+            );
+        }
+        allc = allc
+            .push(field("Rotation address:", &osc.rotation_address, OscField::Rotation))
+            .push(field("Euler angles address:", &osc.euler_address, OscField::Euler))
+            .push(field("Button address:", &osc.button_address, OscField::Button))
+            .push(text(
+                "Addresses can contain {serial} and {button}. Leave an address empty to not send it.",
+            ));
+    }
+    allc
+}
+fn scale_wizard<'a>(wizard: &ScaleWizard, measured_angle: Option<f64>) -> Container<'a, Message> {
+    let axes = ScaleWizard::AXES.iter().enumerate().fold(
+        Row::new().spacing(20).push(text("Axis:")),
+        |row, (i, name)| row.push(radio(*name, i, Some(wizard.axis), Message::ScaleWizardAxis)),
+    );
+    let settings_row = Row::new()
+        .spacing(20)
+        .align_y(Alignment::Center)
+        .push(axes)
+        .push(text("Turns:"))
+        .push(
+            pick_list(
+                ScaleWizard::TURNS,
+                Some(wizard.turns),
+                Message::ScaleWizardTurns,
+            )
+            .padding(5),
+        );
+
+    let mut allc = Column::new()
+        .spacing(10)
+        .push(text(format!("Gyro scale calibration of {}", wizard.serial_number)).size(20))
+        .push(text(
+            "1. Pick the axis to turn around and how many turns to make. \
+            Z is the easiest: lay the Joy-Con flat on a table and turn it around.",
+        ))
+        .push(settings_row)
+        .push(text(
+            "2. Put the Joy-Con against something straight, like the edge of a book, and press Start.",
+        ))
+        .push(text(format!(
+            "3. Turn it exactly {} full turns, put it back against the same edge and press Done.",
+            wizard.turns
+        )));
+    if wizard.measuring {
+        allc = allc.push(text(format!(
+            "Measured so far: {:.2} turns",
+            measured_angle.unwrap_or(0.0).abs() / TAU
+        )));
+    }
+    match &wizard.result {
+        Some(Ok(result)) => {
+            allc = allc.push(container(text(result.clone())).style(style::text_green))
+        }
+        Some(Err(result)) => {
+            allc = allc.push(container(text(result.clone())).style(style::text_orange))
+        }
+        None => {}
+    }
+    let buttons = Row::new()
+        .spacing(10)
+        .push(if wizard.measuring {
+            button("Done")
+                .on_press(Message::ScaleWizardDone)
+                .style(style::button_primary)
+        } else {
+            button("Start")
+                .on_press(Message::ScaleWizardStart)
+                .style(style::button_primary)
+        })
+        .push(
+            button("Close")
+                .on_press(Message::ScaleWizardClose)
+                .style(style::button_primary),
+        );
+    container(allc.push(buttons))
+        .width(Length::Fill)
+        .padding(15)
+        .style(style::item_normal)
+}
+fn accel_wizard<'a>(wizard: &AccelWizard) -> Container<'a, Message> {
+    let faces = joycon::FACES.iter().enumerate().fold(
+        Row::new().spacing(15).push(text("Captured:")),
+        |row, (i, name)| {
+            row.push(container(text(*name)).style(if wizard.faces.has(i) {
+                style::text_green
+            } else {
+                style::text_orange
+            }))
+        },
+    );
+    let mut allc = Column::new()
+        .spacing(10)
+        .push(
+            text(format!(
+                "Accelerometer calibration of {}",
+                wizard.serial_number
+            ))
+            .size(20),
+        )
+        .push(text(
+            "Lay the Joy-Con on a flat table on each of its six sides. \
+             Let it lie still for a moment on each side, then press Capture.",
+        ))
+        .push(faces);
+    match &wizard.result {
+        Some(Ok(result)) => {
+            allc = allc.push(container(text(result.clone())).style(style::text_green))
+        }
+        Some(Err(result)) => {
+            allc = allc.push(container(text(result.clone())).style(style::text_orange))
+        }
+        None => {}
+    }
+    let buttons = Row::new()
+        .spacing(10)
+        .push(
+            button("Capture")
+                .on_press_maybe((!wizard.finished).then_some(Message::AccelWizardCapture))
+                .style(style::button_primary),
+        )
+        .push(
+            button("Close")
+                .on_press(Message::AccelWizardClose)
+                .style(style::button_primary),
+        );
+    container(allc.push(buttons))
+        .width(Length::Fill)
+        .padding(15)
+        .style(style::item_normal)
+}
+fn vmc_settings<'a>(vmc: &settings::Vmc) -> Column<'a, Message> {
+    let mut allc = Column::new().spacing(10).push(
+        checkbox(
+            "Send trackers with the VMC protocol, for VTuber programs. Names can be set on each tracker.",
+            vmc.enabled,
+        )
+        .on_toggle(Message::SettingsVmcToggled),
+    );
+    if vmc.enabled {
+        allc = allc.push(
+            Row::new()
+                .spacing(10)
+                .align_y(Alignment::Center)
+                .push(text("VMC target:").width(Length::Fixed(150.0)))
+                .push(
+                    text_input("127.0.0.1:39539", &vmc.target)
+                        .on_input(Message::VmcTargetChange)
+                        .width(Length::Fixed(300.0))
+                        .padding(10),
+                ),
+        );
+        if vmc.target.parse::<SocketAddr>().is_err() {
+            allc = allc.push(
+                container(text("VMC target is not a valid ip with port number!"))
+                    .style(style::text_yellow),
+            );
+        }
+    }
+    allc
+}
+fn discovery<'a>(enabled: bool, servers: &[SocketAddr]) -> Column<'a, Message> {
+    let mut allc = Column::new().spacing(10).push(
+        checkbox(
+            "Find the SlimeVR Server on the local network instead of using the address above.",
+            enabled,
+        )
+        .on_toggle(Message::SettingsDiscoverToggled),
+    );
+    for &server in servers {
+        allc = allc.push(
+            Row::new()
+                .spacing(10)
+                .align_y(Alignment::Center)
+                .push(text(format!("Found SlimeVR Server at {server}")))
+                .push(
+                    button(text("Use this address"))
+                        .style(style::button_primary)
+                        .on_press(Message::ServerPicked(server)),
+                ),
+        );
+    }
+    allc
+}
 fn top_bar<'a>(update: Option<String>) -> Container<'a, Message> {
     let mut top_column = Row::new()
         .align_y(Alignment::Center)
@@ -314,10 +1166,10 @@ fn bottom_bar<'a>(
                 style::text_yellow
             },
         ))
-        .push(text(if connected == ServerStatus::Connected {
-            format!(" to {address}.")
-        } else {
-            format!(". Trying to connect to {address}{search_dots}")
+        .push(text(match connected {
+            ServerStatus::Connected => format!(" to {address}."),
+            ServerStatus::NetworkError => format!(". Retrying to reach {address}{search_dots}"),
+            _ => format!(". Trying to connect to {address}{search_dots}"),
         }));
     container(status)
         .width(Length::Fill)
@@ -347,14 +1199,14 @@ impl JoyconBoxes {
         self.statuses
             .iter()
             .map(|status| {
+                let height = if settings.vmc.enabled { 625.0 } else { 575.0 };
                 container(single_box_view(
                     status,
                     &self.svg_handler,
                     &self.needle_handler,
-                    settings.joycon_scale_get(&status.serial_number),
-                    settings.joycon_rotation_get(&status.serial_number),
+                    settings,
                 ))
-                .height(Length::Fixed(335.0))
+                .height(Length::Fixed(height))
                 .width(Length::Fixed(300.0))
                 .padding(10)
                 .style(style::item_normal)
@@ -367,10 +1219,27 @@ fn single_box_view<'a>(
     status: &joycon::Status,
     svg_handler: &joycon::Svg,
     needle_handler: &Needle,
-    scale: f64,
-    mount_rot: i32,
+    settings: &WranglerSettings,
 ) -> Column<'a, Message> {
     let sn = status.serial_number.clone();
+    let scale = settings.joycon_scale_get(&sn);
+    let mounting = settings.joycon_mounting_get(&sn);
+    let preset = settings.joycon_preset_get(&sn);
+    let filter = settings.joycon_filter_get(&sn).kind;
+    let vmc_name = settings.vmc.enabled.then(|| {
+        settings
+            .joycon
+            .get(&sn)
+            .map(|j| j.vmc_name.clone())
+            .unwrap_or_default()
+    });
+    let vmc_sn = sn.clone();
+    let filter_sn = sn.clone();
+    let wizard_sn = sn.clone();
+    let preset_sn = sn.clone();
+    let reset_sn = sn.clone();
+    let accel_sn = sn.clone();
+    let accel_calibrated = settings.joycon_accel_calibration_get(&sn).is_calibrated();
 
     let buttons = Row::new()
         .spacing(10)
@@ -385,7 +1254,7 @@ fn single_box_view<'a>(
                 .style(style::button_primary),
         );
 
-    let svg = Svg::new(svg_handler.get(&status.design, mount_rot));
+    let svg = Svg::new(svg_handler.get(&status.design, &mounting));
 
     let left = Column::new()
         .spacing(10)
@@ -449,15 +1318,42 @@ fn single_box_view<'a>(
         DeviceStatus::Healthy => style::text_green,
     });
 
+    // The slider sets all axes, after calibrating they can be different.
+    let scale_all = scale.iter().sum::<f64>() / 3.0;
+    let scale_text = if scale.iter().all(|s| *s == scale[0]) {
+        format!("Rotation scale ratio: {:.3}", scale[0])
+    } else {
+        format!(
+            "Rotation scale ratio: {:.3}/{:.3}/{:.3}",
+            scale[0], scale[1], scale[2]
+        )
+    };
+
+    let bias_text = if status.bias_converged {
+        container(text("calibrated")).style(style::text_green)
+    } else {
+        container(text("lay it still to calibrate")).style(style::text_yellow)
+    };
+
     let bottom = Column::new()
         .spacing(10)
         .push(
-            slider(0.8..=1.2, scale, move |c| {
+            slider(settings::GYRO_SCALE_RANGE, scale_all, move |c| {
                 Message::JoyconScale(sn.clone(), c)
             })
             .step(0.001),
         )
-        .push(text(format!("Rotation scale ratio: {scale:.3}")))
+        .push(
+            Row::new()
+                .align_y(Alignment::Center)
+                .push(text(scale_text))
+                .push(horizontal_space())
+                .push(
+                    button("Calibrate")
+                        .on_press(Message::ScaleWizardOpen(wizard_sn))
+                        .style(style::button_primary),
+                ),
+        )
         .push(
             text(
                 "Change this if the tracker in vr moves less or more than your irl joycon. Higher value = more movement.",
@@ -465,7 +1361,109 @@ fn single_box_view<'a>(
             .size(14),
         )
         .push(Row::new().push(text("Battery level: ")).push(battery_text))
-        .push(Row::new().push(text("Status: ")).push(status_text));
+        .push(
+            Row::new()
+                .push(text("Status: "))
+                .push(status_text)
+                .push(text(format!(", {} packets sent", status.packets_sent))),
+        )
+        .push(
+            Row::new()
+                .push(text(format!(
+                    "Gyro bias: {:.2}, {:.2}, {:.2} °/s, ",
+                    status.gyro_bias[0].to_degrees(),
+                    status.gyro_bias[1].to_degrees(),
+                    status.gyro_bias[2].to_degrees(),
+                )))
+                .push(bias_text),
+        )
+        .push(Row::new().push(text("IMU temperature: ")).push(
+            match status.temperature {
+                Some(temperature) if status.drift_compensated => container(text(format!(
+                    "{temperature:.1} °C, drift compensated"
+                )))
+                .style(style::text_green),
+                Some(temperature) => {
+                    container(text(format!("{temperature:.1} °C, learning drift")))
+                        .style(style::text_yellow)
+                }
+                None => container(text("unknown")),
+            },
+        ))
+        .push(
+            Row::new()
+                .align_y(Alignment::Center)
+                .push(text("Accelerometer: "))
+                .push(if accel_calibrated {
+                    container(text("calibrated")).style(style::text_green)
+                } else {
+                    container(text("not calibrated")).style(style::text_yellow)
+                })
+                .push(horizontal_space())
+                .push(
+                    button("Calibrate")
+                        .on_press(Message::AccelWizardOpen(accel_sn))
+                        .style(style::button_primary),
+                ),
+        );
 
-    Column::new().spacing(10).push(top).push(bottom)
+    let mounting_row = Row::new()
+        .spacing(10)
+        .align_y(Alignment::Center)
+        .push(text("Mounting:"))
+        .push(
+            pick_list(MountingPreset::ALL, preset, move |preset| {
+                Message::JoyconPreset(preset_sn.clone(), preset)
+            })
+            .placeholder("Custom")
+            .padding(5),
+        );
+
+    let filter_row = Row::new()
+        .spacing(10)
+        .align_y(Alignment::Center)
+        .push(text("Filter:"))
+        .push(
+            pick_list(FilterKind::ALL, Some(filter), move |kind| {
+                Message::JoyconFilter(filter_sn.clone(), kind)
+            })
+            .padding(5),
+        );
+
+    let reset_row = Row::new()
+        .spacing(10)
+        .align_y(Alignment::Center)
+        .push(text("Reset:"))
+        .push(
+            button("Yaw")
+                .on_press(Message::JoyconReset(reset_sn.clone(), ResetKind::Yaw))
+                .style(style::button_primary),
+        )
+        .push(
+            button("Full")
+                .on_press(Message::JoyconReset(reset_sn, ResetKind::Full))
+                .style(style::button_primary),
+        );
+
+    let mut allc = Column::new()
+        .spacing(10)
+        .push(top)
+        .push(bottom)
+        .push(mounting_row)
+        .push(filter_row)
+        .push(reset_row);
+    if let Some(name) = vmc_name {
+        allc = allc.push(
+            Row::new()
+                .spacing(10)
+                .align_y(Alignment::Center)
+                .push(text("VMC name:"))
+                .push(
+                    text_input(&status.serial_number, &name)
+                        .on_input(move |n| Message::JoyconVmcName(vmc_sn.clone(), n))
+                        .padding(5),
+                ),
+        );
+    }
+    allc
 }
@@ -1,34 +1,388 @@
 use std::{
-    collections::HashMap, fs, fs::File, io::BufReader, net::SocketAddr, path::PathBuf, sync::Arc,
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{Arc, Mutex},
 };
 
 use arc_swap::{ArcSwap, Guard};
 use directories::ProjectDirs;
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 fn file_name() -> Option<PathBuf> {
     ProjectDirs::from("", "", "SlimeVR Wrangler").map(|pd| pd.config_dir().join("config.json"))
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Joycon {
+    /// Replaced by `mounting`, only read from older configs.
+    #[serde(default, skip_serializing)]
+    rotation: i32,
+    /// Rotation from the tracker to the Joy-Con as a quaternion (w, x, y, z), none if the same.
     #[serde(default)]
-    pub rotation: i32,
-    #[serde(default = "return_f64_one")]
-    pub gyro_scale_factor: f64,
+    pub mounting: Option<[f64; 4]>,
+    /// Per axis, older configs have one value for all axes.
+    #[serde(default = "return_scale_one", deserialize_with = "deserialize_scale")]
+    pub gyro_scale_factor: [f64; 3],
     #[serde(default)]
     pub keep_id: u8,
+    /// Tracker name for VMC, the serial number is used if empty.
+    #[serde(default)]
+    pub vmc_name: String,
+    #[serde(default)]
+    pub filter: FilterSettings,
+    /// Gyro bias in radians/s, found while lying still.
+    #[serde(default)]
+    pub gyro_bias: [f64; 3],
+    #[serde(default)]
+    pub accel_calibration: AccelCalibration,
+    #[serde(default)]
+    pub temperature_drift: TemperatureDrift,
 }
-fn return_f64_one() -> f64 {
-    1.0
+/// Gyro scale ratios that can be picked for a Joy-Con.
+pub const GYRO_SCALE_RANGE: RangeInclusive<f64> = 0.8..=1.2;
+
+fn return_scale_one() -> [f64; 3] {
+    [1.0; 3]
+}
+
+fn deserialize_scale<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f64; 3], D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scale {
+        All(f64),
+        Axes([f64; 3]),
+    }
+    Ok(match Scale::deserialize(deserializer)? {
+        Scale::All(scale) => [scale; 3],
+        Scale::Axes(axes) => axes,
+    })
 }
 
 impl Default for Joycon {
     fn default() -> Self {
         Joycon {
             rotation: 0,
-            gyro_scale_factor: 1.0,
+            mounting: None,
+            gyro_scale_factor: [1.0; 3],
             keep_id: 0,
+            vmc_name: String::new(),
+            filter: FilterSettings::default(),
+            gyro_bias: [0.0; 3],
+            accel_calibration: AccelCalibration::default(),
+            temperature_drift: TemperatureDrift::default(),
+        }
+    }
+}
+
+impl Joycon {
+    fn migrate(&mut self) {
+        if self.rotation != 0 && self.mounting.is_none() {
+            self.set_mounting(z_rotation(self.rotation as f64));
+        }
+        self.rotation = 0;
+    }
+    pub fn mounting(&self) -> UnitQuaternion<f64> {
+        self.mounting
+            .map_or_else(UnitQuaternion::identity, |[w, x, y, z]| {
+                UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z))
+            })
+    }
+    fn set_mounting(&mut self, mounting: UnitQuaternion<f64>) {
+        let q = mounting.quaternion();
+        self.mounting = Some([q.w, q.i, q.j, q.k]);
+    }
+}
+
+fn z_rotation(degrees: f64) -> UnitQuaternion<f64> {
+    UnitQuaternion::from_axis_angle(&Vector3::z_axis(), degrees.to_radians())
+}
+
+/// Degrees the mounting turns around the face of the Joy-Con, the part that the rotate buttons
+/// change.
+pub fn mounting_twist(mounting: &UnitQuaternion<f64>) -> f64 {
+    let q = mounting.quaternion();
+    // Half of the angle. When the Joy-Con is flipped over, w and k are both 0 and i and j have it.
+    let half = if q.w.hypot(q.k) > 1e-6 {
+        q.k.atan2(q.w)
+    } else {
+        (-q.j).atan2(q.i)
+    };
+    (2.0 * half).to_degrees()
+}
+
+/// Mounting that makes the tracker point up while the Joy-Con is at `rotation`, for capturing
+/// the mounting while standing in a T-pose.
+pub fn mounting_from_tpose(rotation: UnitQuaternion<f64>) -> UnitQuaternion<f64> {
+    let up = rotation.inverse_transform_vector(&Vector3::z());
+    UnitQuaternion::rotation_between(&Vector3::z(), &up).unwrap_or_else(|| {
+        UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f64::consts::PI)
+    })
+}
+
+/// Common ways to strap on a Joy-Con, turned around its long side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountingPreset {
+    ButtonsOut,
+    ButtonsIn,
+    SideLeft,
+    SideRight,
+}
+impl MountingPreset {
+    pub const ALL: [MountingPreset; 4] = [
+        MountingPreset::ButtonsOut,
+        MountingPreset::ButtonsIn,
+        MountingPreset::SideLeft,
+        MountingPreset::SideRight,
+    ];
+    /// The preset, turned `twist` degrees around the face like the rotate buttons do.
+    fn mounting(self, twist: f64) -> UnitQuaternion<f64> {
+        let degrees: f64 = match self {
+            MountingPreset::ButtonsOut => 0.0,
+            MountingPreset::ButtonsIn => 180.0,
+            MountingPreset::SideLeft => 90.0,
+            MountingPreset::SideRight => -90.0,
+        };
+        UnitQuaternion::from_axis_angle(&Vector3::x_axis(), degrees.to_radians())
+            * z_rotation(twist)
+    }
+}
+impl Display for MountingPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MountingPreset::ButtonsOut => "Buttons out",
+            MountingPreset::ButtonsIn => "Buttons in",
+            MountingPreset::SideLeft => "On its left side",
+            MountingPreset::SideRight => "On its right side",
+        })
+    }
+}
+
+/// Corrects each accelerometer axis as `(acc - offset) * scale`, found with the six-face
+/// calibration.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct AccelCalibration {
+    pub offset: [f64; 3],
+    pub scale: [f64; 3],
+}
+impl AccelCalibration {
+    pub fn apply(&self, acc: [f64; 3]) -> [f64; 3] {
+        [0, 1, 2].map(|i| (acc[i] - self.offset[i]) * self.scale[i])
+    }
+    pub fn is_calibrated(&self) -> bool {
+        *self != Self::default()
+    }
+}
+impl Default for AccelCalibration {
+    fn default() -> Self {
+        AccelCalibration {
+            offset: [0.0; 3],
+            scale: [1.0; 3],
+        }
+    }
+}
+
+/// Gyro bias measured at different IMU temperatures, with a line fitted through it per axis.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct TemperatureDrift {
+    /// Average bias at each whole degree celsius it was measured at.
+    pub points: Vec<DriftPoint>,
+    /// Temperature in celsius where the fitted line goes through the average bias.
+    pub reference: f64,
+    /// Change of the bias in radians/s per degree celsius, zero until enough is measured.
+    pub slope: [f64; 3],
+}
+impl TemperatureDrift {
+    /// The part of the bias that comes from being at `temperature`.
+    pub fn bias(&self, temperature: f64) -> [f64; 3] {
+        self.slope
+            .map(|slope| slope * (temperature - self.reference))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct DriftPoint {
+    pub temperature: f64,
+    /// Gyro bias in radians/s.
+    pub bias: [f64; 3],
+    /// How many measurements went into the average.
+    pub weight: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterKind {
+    #[default]
+    Vqf,
+    Madgwick,
+    Mahony,
+}
+impl FilterKind {
+    pub const ALL: [FilterKind; 3] = [FilterKind::Vqf, FilterKind::Madgwick, FilterKind::Mahony];
+}
+impl Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FilterKind::Vqf => "VQF",
+            FilterKind::Madgwick => "Madgwick",
+            FilterKind::Mahony => "Mahony",
+        })
+    }
+}
+
+/// Which orientation filter a Joy-Con uses, and how it is tuned.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct FilterSettings {
+    pub kind: FilterKind,
+    /// VQF: time constant in seconds for correcting the rotation with the accelerometer.
+    pub tau_acc: f64,
+    /// VQF: estimate the gyro bias while the controller is lying still.
+    pub rest_detection: bool,
+    /// VQF: estimate the gyro bias while moving. Mahony: use the integral gain.
+    pub bias_estimation: bool,
+    /// Madgwick: how fast the accelerometer corrects the rotation.
+    pub beta: f64,
+    /// Mahony: proportional gain.
+    pub kp: f64,
+    /// Mahony: integral gain.
+    pub ki: f64,
+}
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings {
+            kind: FilterKind::Vqf,
+            tau_acc: 3.0,
+            rest_detection: true,
+            bias_estimation: true,
+            beta: 0.1,
+            kp: 0.5,
+            ki: 0.01,
+        }
+    }
+}
+
+/// Where to send tracker data over OSC. Addresses can contain `{serial}` and `{button}`, and
+/// empty addresses are not sent.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Osc {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "return_osc_target")]
+    pub target: String,
+    #[serde(default = "return_osc_rotation")]
+    pub rotation_address: String,
+    #[serde(default = "return_osc_euler")]
+    pub euler_address: String,
+    #[serde(default = "return_osc_button")]
+    pub button_address: String,
+}
+fn return_osc_target() -> String {
+    "127.0.0.1:9000".into()
+}
+fn return_osc_rotation() -> String {
+    "/wrangler/{serial}/rotation".into()
+}
+fn return_osc_euler() -> String {
+    "/wrangler/{serial}/euler".into()
+}
+fn return_osc_button() -> String {
+    "/wrangler/{serial}/button/{button}".into()
+}
+
+impl Default for Osc {
+    fn default() -> Self {
+        Osc {
+            enabled: false,
+            target: return_osc_target(),
+            rotation_address: return_osc_rotation(),
+            euler_address: return_osc_euler(),
+            button_address: return_osc_button(),
+        }
+    }
+}
+
+/// Where to send trackers with the Virtual Motion Capture protocol, as a VMC performer.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Vmc {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "return_vmc_target")]
+    pub target: String,
+}
+fn return_vmc_target() -> String {
+    "127.0.0.1:39539".into()
+}
+
+impl Default for Vmc {
+    fn default() -> Self {
+        Vmc {
+            enabled: false,
+            target: return_vmc_target(),
+        }
+    }
+}
+
+/// Tapping on a Joy-Con, for when its buttons can't be reached.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Taps {
+    pub enabled: bool,
+    /// How hard a jolt has to be to count as a tap, in G.
+    pub threshold: f64,
+    /// Most seconds between two taps of the same pattern.
+    pub max_gap: f64,
+    pub mappings: Vec<TapMapping>,
+}
+/// Tap thresholds that can be picked, in G.
+pub const TAP_THRESHOLD_RANGE: RangeInclusive<f64> = 0.5..=4.0;
+/// Longest gaps between taps that can be picked, in seconds.
+pub const TAP_GAP_RANGE: RangeInclusive<f64> = 0.2..=1.0;
+
+impl Default for Taps {
+    fn default() -> Self {
+        Taps {
+            enabled: false,
+            threshold: 1.5,
+            max_gap: 0.4,
+            mappings: vec![
+                TapMapping {
+                    taps: 2,
+                    ..Default::default()
+                },
+                TapMapping {
+                    taps: 3,
+                    action: ButtonAction::FullReset,
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+}
+
+/// Runs `action` when a Joy-Con is tapped `taps` times in a row.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TapMapping {
+    pub taps: u32,
+    pub action: ButtonAction,
+    /// Address for `ButtonAction::Osc`, can contain {serial}.
+    #[serde(default)]
+    pub osc_address: String,
+}
+impl Default for TapMapping {
+    fn default() -> Self {
+        TapMapping {
+            taps: 2,
+            action: ButtonAction::QuickReset,
+            osc_address: ButtonMapping::default().osc_address,
         }
     }
 }
@@ -40,10 +394,114 @@ pub struct WranglerSettings {
     pub joycon: HashMap<String, Joycon>,
     #[serde(default = "return_true")]
     pub send_reset: bool,
+    #[serde(default = "return_button_mappings")]
+    pub button_mappings: Vec<ButtonMapping>,
     #[serde(default = "return_mac")]
     pub emulated_mac: [u8; 6],
     #[serde(default = "return_false")]
     pub keep_ids: bool,
+    #[serde(default = "return_false")]
+    pub bundle_packets: bool,
+    #[serde(default = "return_false")]
+    pub separate_trackers: bool,
+    #[serde(default = "return_false")]
+    pub discover_server: bool,
+    #[serde(default = "return_false")]
+    pub api_enabled: bool,
+    #[serde(default = "return_api_port")]
+    pub api_port: u16,
+    #[serde(default)]
+    pub osc: Osc,
+    #[serde(default)]
+    pub vmc: Vmc,
+    #[serde(default)]
+    pub taps: Taps,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonAction {
+    QuickReset,
+    FullReset,
+    /// Asks the server for a mounting reset.
+    MountingReset,
+    /// Asks the server to pause or resume tracking.
+    PauseTracking,
+    /// Sends an OSC message to the OSC target.
+    Osc,
+}
+impl ButtonAction {
+    pub const ALL: [ButtonAction; 5] = [
+        ButtonAction::QuickReset,
+        ButtonAction::FullReset,
+        ButtonAction::MountingReset,
+        ButtonAction::PauseTracking,
+        ButtonAction::Osc,
+    ];
+}
+impl Display for ButtonAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ButtonAction::QuickReset => "Quick reset",
+            ButtonAction::FullReset => "Full reset",
+            ButtonAction::MountingReset => "Mounting reset",
+            ButtonAction::PauseTracking => "Pause tracking",
+            ButtonAction::Osc => "OSC message",
+        })
+    }
+}
+
+/// Runs `action` once the `buttons` have been held down together for `hold` seconds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ButtonMapping {
+    /// Like "l+up", read with `joycon::parse_combo`.
+    pub buttons: String,
+    #[serde(default)]
+    pub hold: f64,
+    pub action: ButtonAction,
+    /// Address for `ButtonAction::Osc`, can contain {serial}.
+    #[serde(default)]
+    pub osc_address: String,
+}
+impl Default for ButtonMapping {
+    fn default() -> Self {
+        ButtonMapping {
+            buttons: String::new(),
+            hold: 0.0,
+            action: ButtonAction::QuickReset,
+            osc_address: "/wrangler/{serial}/event".into(),
+        }
+    }
+}
+
+/// The on/off settings, for changing them by name from outside the GUI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BoolSetting {
+    SendReset,
+    KeepIds,
+    BundlePackets,
+    SeparateTrackers,
+    DiscoverServer,
+    ApiEnabled,
+    Osc,
+    Vmc,
+    Taps,
+}
+impl BoolSetting {
+    pub fn get_mut(self, ws: &mut WranglerSettings) -> &mut bool {
+        match self {
+            BoolSetting::SendReset => &mut ws.send_reset,
+            BoolSetting::KeepIds => &mut ws.keep_ids,
+            BoolSetting::BundlePackets => &mut ws.bundle_packets,
+            BoolSetting::SeparateTrackers => &mut ws.separate_trackers,
+            BoolSetting::DiscoverServer => &mut ws.discover_server,
+            BoolSetting::ApiEnabled => &mut ws.api_enabled,
+            BoolSetting::Osc => &mut ws.osc.enabled,
+            BoolSetting::Vmc => &mut ws.vmc.enabled,
+            BoolSetting::Taps => &mut ws.taps.enabled,
+        }
+    }
 }
 
 fn return_true() -> bool {
@@ -52,6 +510,23 @@ fn return_true() -> bool {
 fn return_false() -> bool {
     false
 }
+fn return_button_mappings() -> Vec<ButtonMapping> {
+    [
+        ("up", ButtonAction::QuickReset),
+        ("b", ButtonAction::QuickReset),
+        ("l+up", ButtonAction::FullReset),
+        ("r+b", ButtonAction::FullReset),
+    ]
+    .map(|(buttons, action)| ButtonMapping {
+        buttons: buttons.into(),
+        action,
+        ..Default::default()
+    })
+    .into()
+}
+fn return_api_port() -> u16 {
+    47590
+}
 fn return_mac() -> [u8; 6] {
     let mut r = rand::rng();
     [0x00, 0x0F, r.random(), r.random(), r.random(), r.random()]
@@ -69,35 +544,131 @@ impl WranglerSettings {
             .ok()
             .and_then(|file| serde_json::to_writer_pretty(file, self).ok());
     }
+    /// Settings used when there is no config file yet.
+    fn initial() -> Self {
+        Self {
+            address: DEFAULT_ADDR.into(),
+            joycon: HashMap::new(),
+            send_reset: true,
+            button_mappings: return_button_mappings(),
+            emulated_mac: return_mac(),
+            keep_ids: false,
+            bundle_packets: false,
+            separate_trackers: false,
+            discover_server: false,
+            api_enabled: false,
+            api_port: return_api_port(),
+            osc: Osc::default(),
+            vmc: Vmc::default(),
+            taps: Taps::default(),
+        }
+    }
     pub fn load_and_save() -> Self {
-        let settings = file_name()
+        let mut settings: Self = file_name()
             .and_then(|path| File::open(path).ok())
             .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
-            .unwrap_or_else(|| Self {
-                address: DEFAULT_ADDR.into(),
-                joycon: HashMap::new(),
-                send_reset: true,
-                emulated_mac: return_mac(),
-                keep_ids: false,
-            });
+            .unwrap_or_else(Self::initial);
+        for joycon in settings.joycon.values_mut() {
+            joycon.migrate();
+        }
         settings.save();
         settings
     }
+    /// Turns the mounting around the face of the Joy-Con.
     pub fn joycon_rotation_add(&mut self, serial_number: String, degrees: i32) {
         let entry = self.joycon.entry(serial_number).or_default();
-        entry.rotation = (entry.rotation + degrees).rem_euclid(360);
+        entry.set_mounting(entry.mounting() * z_rotation(degrees as f64));
     }
+    /// How far the mounting turns around the face of the Joy-Con, for showing it.
     pub fn joycon_rotation_get(&self, serial_number: &str) -> i32 {
-        self.joycon.get(serial_number).map_or(0, |j| j.rotation)
+        let twist = mounting_twist(&self.joycon_mounting_get(serial_number));
+        (twist.round() as i32).rem_euclid(360)
+    }
+    pub fn joycon_mounting_set(&mut self, serial_number: String, mounting: UnitQuaternion<f64>) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.set_mounting(mounting);
+    }
+    pub fn joycon_mounting_get(&self, serial_number: &str) -> UnitQuaternion<f64> {
+        self.joycon
+            .get(serial_number)
+            .map_or_else(UnitQuaternion::identity, Joycon::mounting)
+    }
+    /// Changes to a preset, but keeps the turn from the rotate buttons.
+    pub fn joycon_preset_set(&mut self, serial_number: String, preset: MountingPreset) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        let twist = mounting_twist(&entry.mounting());
+        entry.set_mounting(preset.mounting(twist));
+    }
+    /// The preset the mounting matches, if any.
+    pub fn joycon_preset_get(&self, serial_number: &str) -> Option<MountingPreset> {
+        let mounting = self.joycon_mounting_get(serial_number);
+        let twist = mounting_twist(&mounting);
+        MountingPreset::ALL
+            .into_iter()
+            .find(|preset| preset.mounting(twist).angle_to(&mounting) < 0.01)
     }
     pub fn joycon_scale_set(&mut self, serial_number: String, scale: f64) {
         let entry = self.joycon.entry(serial_number).or_default();
-        entry.gyro_scale_factor = scale;
+        entry.gyro_scale_factor = [scale; 3];
     }
-    pub fn joycon_scale_get(&self, serial_number: &str) -> f64 {
+    pub fn joycon_axis_scale_set(&mut self, serial_number: String, axis: usize, scale: f64) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.gyro_scale_factor[axis] = scale;
+    }
+    pub fn joycon_scale_get(&self, serial_number: &str) -> [f64; 3] {
+        self.joycon
+            .get(serial_number)
+            .map_or([1.0; 3], |j| j.gyro_scale_factor)
+    }
+    pub fn joycon_vmc_name_set(&mut self, serial_number: String, name: String) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.vmc_name = name;
+    }
+    pub fn joycon_vmc_name_get<'a>(&'a self, serial_number: &'a str) -> &'a str {
+        match self.joycon.get(serial_number) {
+            Some(j) if !j.vmc_name.is_empty() => &j.vmc_name,
+            _ => serial_number,
+        }
+    }
+    pub fn joycon_filter_set(&mut self, serial_number: String, kind: FilterKind) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.filter.kind = kind;
+    }
+    pub fn joycon_filter_get(&self, serial_number: &str) -> FilterSettings {
+        self.joycon
+            .get(serial_number)
+            .map_or_else(FilterSettings::default, |j| j.filter)
+    }
+    pub fn joycon_gyro_bias_set(&mut self, serial_number: String, bias: [f64; 3]) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.gyro_bias = bias;
+    }
+    pub fn joycon_gyro_bias_get(&self, serial_number: &str) -> [f64; 3] {
+        self.joycon
+            .get(serial_number)
+            .map_or([0.0; 3], |j| j.gyro_bias)
+    }
+    pub fn joycon_accel_calibration_set(
+        &mut self,
+        serial_number: String,
+        calibration: AccelCalibration,
+    ) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.accel_calibration = calibration;
+    }
+    pub fn joycon_accel_calibration_get(&self, serial_number: &str) -> AccelCalibration {
+        self.joycon
+            .get(serial_number)
+            .map_or_else(AccelCalibration::default, |j| j.accel_calibration)
+    }
+    pub fn joycon_temperature_drift_set(&mut self, serial_number: String, drift: TemperatureDrift) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.temperature_drift = drift;
+    }
+    pub fn joycon_temperature_drift_get(&self, serial_number: &str) -> TemperatureDrift {
         self.joycon
             .get(serial_number)
-            .map_or(1.0, |j| j.gyro_scale_factor)
+            .map_or_else(TemperatureDrift::default, |j| j.temperature_drift.clone())
     }
     fn joycon_keep_id_set_new(&mut self, serial_number: String) {
         let max = self.joycon.values().map(|j| j.keep_id).max();
@@ -123,6 +694,10 @@ impl Default for WranglerSettings {
 #[derive(Default, Clone)]
 pub struct Handler {
     arc: Arc<ArcSwap<WranglerSettings>>,
+    /// Held across a whole change so writers from different threads don't undo each other.
+    writer: Arc<Mutex<()>>,
+    /// Changes are not saved to the config file.
+    in_memory: bool,
 }
 impl Handler {
     pub fn load(&self) -> Guard<Arc<WranglerSettings>> {
@@ -132,9 +707,33 @@ impl Handler {
     where
         T: FnOnce(&mut WranglerSettings),
     {
+        let in_memory = self.in_memory;
+        self.change_with(func, |ws| {
+            if !in_memory {
+                ws.save();
+            }
+        });
+    }
+    /// Settings that start from the defaults and are never saved, for tests.
+    #[cfg(test)]
+    pub fn in_memory(func: impl FnOnce(&mut WranglerSettings)) -> Self {
+        let mut settings = WranglerSettings::initial();
+        func(&mut settings);
+        Self {
+            arc: Arc::new(ArcSwap::from_pointee(settings)),
+            writer: Default::default(),
+            in_memory: true,
+        }
+    }
+    fn change_with<T, S>(&self, func: T, save: S)
+    where
+        T: FnOnce(&mut WranglerSettings),
+        S: FnOnce(&WranglerSettings),
+    {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
         let mut current = (**self.arc.load()).clone();
         func(&mut current);
-        current.save();
+        save(&current);
         self.arc.store(Arc::new(current));
     }
     pub fn joycon_keep_id(&self, serial_number: String) -> u8 {
@@ -153,3 +752,76 @@ impl Handler {
             .map_or(0, |j| j.keep_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_changes_are_kept() {
+        let handler = Handler::in_memory(|_| {});
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        handler.change_with(
+                            |ws| {
+                                ws.joycon.entry(format!("{t}-{i}")).or_default();
+                            },
+                            |_| std::thread::yield_now(),
+                        );
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(handler.load().joycon.len(), 100);
+    }
+
+    #[test]
+    fn mounting_presets() {
+        let mut joycon: Joycon = serde_json::from_str(r#"{"rotation": 90}"#).unwrap();
+        joycon.migrate();
+        let mut settings = WranglerSettings::initial();
+        settings.joycon = HashMap::from([("a".to_string(), joycon)]);
+
+        assert_eq!(settings.joycon_rotation_get("a"), 90);
+        assert_eq!(
+            settings.joycon_preset_get("a"),
+            Some(MountingPreset::ButtonsOut)
+        );
+        settings.joycon_preset_set("a".into(), MountingPreset::ButtonsIn);
+        assert_eq!(settings.joycon_rotation_get("a"), 90);
+        assert_eq!(
+            settings.joycon_preset_get("a"),
+            Some(MountingPreset::ButtonsIn)
+        );
+        settings.joycon_rotation_add("a".into(), 180);
+        assert_eq!(settings.joycon_rotation_get("a"), 270);
+        assert_eq!(
+            settings.joycon_preset_get("a"),
+            Some(MountingPreset::ButtonsIn)
+        );
+
+        let tilted = UnitQuaternion::from_euler_angles(0.3, -1.2, 2.0);
+        let mounting = mounting_from_tpose(tilted);
+        settings.joycon_mounting_set("a".into(), mounting);
+        assert_eq!(settings.joycon_preset_get("a"), None);
+        let up = (tilted * mounting) * Vector3::z();
+        assert!((up - Vector3::z()).norm() < 1e-9);
+    }
+
+    #[test]
+    fn reads_scale_from_older_config() {
+        let joycon: Joycon = serde_json::from_str(r#"{"gyro_scale_factor": 1.05}"#).unwrap();
+        assert_eq!(joycon.gyro_scale_factor, [1.05; 3]);
+        let joycon: Joycon =
+            serde_json::from_str(r#"{"gyro_scale_factor": [1.0, 0.9, 1.1]}"#).unwrap();
+        assert_eq!(joycon.gyro_scale_factor, [1.0, 0.9, 1.1]);
+        let joycon: Joycon = serde_json::from_str("{}").unwrap();
+        assert_eq!(joycon.gyro_scale_factor, [1.0; 3]);
+    }
+}
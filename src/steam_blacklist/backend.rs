@@ -12,7 +12,7 @@ fn check_valid(config: &Vdf) -> Result<(), BlacklistError> {
         .value
         .get_obj()
         .and_then(|o| o.get("Software"))
-        .map_or(false, |s| !s.is_empty())
+        .is_some_and(|s| !s.is_empty())
         .then_some(())
         .ok_or(BlacklistError::Invalid)
 }